@@ -0,0 +1,386 @@
+//! HTTPアクセスログの実装
+//!
+//! 全てのリクエストをCombined Log FormatもしくはJSONで記録する。
+//! パスワード等のクエリパラメータは値を伏せてから書き込む
+
+use std::{
+  fmt::Write, net::IpAddr, path::Path, sync::OnceLock,
+  time::Instant,
+};
+
+use axum::{
+  extract::{ConnectInfo, Request},
+  http::{HeaderMap, header},
+  middleware::Next,
+  response::Response,
+};
+use chrono::Local;
+use http_body::Body;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+  listener::PeerAddr,
+  logging::{LogRotationConfig, RotatingFile},
+};
+
+/// アクセスログの出力形式
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+pub enum AccessLogFormat {
+  /// Combined Log Formatの末尾に処理時間(マイクロ秒)とリクエストIDを付けたもの
+  #[serde(alias = "combined")]
+  Combined,
+
+  /// 一行一リクエストのJSON
+  #[serde(alias = "json")]
+  Json,
+}
+
+/// アクセスログについてのコンフィグ
+#[derive(Deserialize, Serialize, Clone)]
+pub struct AccessLogConfig {
+  /// 出力先(Noneで無効)
+  pub file: Option<String>,
+
+  pub format: AccessLogFormat,
+
+  /// `X-Forwarded-For`を信用するリバースプロキシのアドレス
+  pub trusted_proxies: Vec<IpAddr>,
+
+  /// 値を伏せるクエリパラメータの名前
+  pub redact_params: Vec<String>,
+
+  pub rotation: LogRotationConfig,
+}
+impl Default for AccessLogConfig {
+  fn default() -> Self {
+    Self {
+      file: Some("tmdx4-workplace-access.log".into()),
+      format: AccessLogFormat::Combined,
+      trusted_proxies: Vec::new(),
+      redact_params: vec![
+        "admin-password".into(),
+        "new-password".into(),
+        "new-password-verify".into(),
+      ],
+      rotation: LogRotationConfig::default(),
+    }
+  }
+}
+
+const REDACTED: &str = "REDACTED";
+
+static ACCESS_LOG: OnceLock<Mutex<RotatingFile>> =
+  OnceLock::new();
+
+/// コンフィグに従ってアクセスログのファイルを開く
+pub fn init(
+  config: &crate::config::Config,
+) -> Result<(), Box<dyn std::error::Error>> {
+  let Some(file) = config.access_log.file.as_ref() else {
+    return Ok(());
+  };
+  let file = RotatingFile::open(
+    Path::new(file),
+    config.access_log.rotation.clone(),
+  )?;
+  let _ = ACCESS_LOG.set(Mutex::new(file));
+  Ok(())
+}
+
+/// 再読み込みしたコンフィグのローテーション設定を反映する
+/// (出力先のファイルは再起動するまで変わらない)
+pub fn reconfigure(config: &crate::config::Config) {
+  if let Some(file) = ACCESS_LOG.get() {
+    file.lock().set_rotation(config.access_log.rotation.clone());
+  }
+}
+
+/// 書き込み途中のアクセスログを吐き出す
+pub fn flush() {
+  if let Some(file) = ACCESS_LOG.get() {
+    let _ = file.lock().flush();
+  }
+}
+
+/// クエリ文字列中の指定されたパラメータの値を伏せる
+/// パラメータ名はパーセントエンコード(`+`は空白)を戻してから比べ、
+/// 名前に`=`がエンコードされて値が続くものも伏せる
+fn redact_query(
+  query: &str,
+  redact_params: &[String],
+) -> String {
+  query
+    .split('&')
+    .map(|pair| {
+      let raw_key =
+        pair.split_once('=').map_or(pair, |(k, _)| k);
+      let key = form_urlencoded::parse(raw_key.as_bytes())
+        .next()
+        .map(|(key, _)| key)
+        .unwrap_or_default();
+      match redact_params.iter().find(|param| {
+        key == param.as_str()
+          || key
+            .strip_prefix(param.as_str())
+            .is_some_and(|rest| rest.starts_with('='))
+      }) {
+        Some(param) => format!("{param}={REDACTED}"),
+        None => pair.to_string(),
+      }
+    })
+    .collect::<Vec<_>>()
+    .join("&")
+}
+
+/// URIもしくはURLのクエリ部分の値を伏せる
+fn redact_uri(uri: &str, redact_params: &[String]) -> String {
+  match uri.split_once('?') {
+    Some((path, query)) => {
+      format!("{path}?{}", redact_query(query, redact_params))
+    }
+    None => uri.to_string(),
+  }
+}
+
+/// 信用するプロキシを経由していれば`X-Forwarded-For`から接続元を求める
+/// 右から辿り、信用しないアドレスが見つかればそれを接続元とする。
+/// 解釈できない値に当たったら、それより左は偽装されうるので直前に辿ったアドレスとする
+/// (Unixドメインソケット経由の接続は常に信用するプロキシからとみなす)
+pub(crate) fn client_ip(
+  peer: Option<PeerAddr>,
+  headers: &HeaderMap,
+  trusted_proxies: &[IpAddr],
+) -> Option<IpAddr> {
+  let mut client = match peer? {
+    PeerAddr::Tcp(addr) | PeerAddr::Tls(addr)
+      if !trusted_proxies.contains(&addr.ip()) =>
+    {
+      return Some(addr.ip());
+    }
+    PeerAddr::Tcp(addr) | PeerAddr::Tls(addr) => Some(addr.ip()),
+    PeerAddr::Unix => None,
+  };
+  let values = headers.get_all("x-forwarded-for");
+  for value in values.iter().rev() {
+    let Ok(value) = value.to_str() else {
+      return client;
+    };
+    for entry in value.rsplit(',') {
+      let Ok(ip) = entry.trim().parse::<IpAddr>() else {
+        return client;
+      };
+      client = Some(ip);
+      if !trusted_proxies.contains(&ip) {
+        return client;
+      }
+    }
+  }
+  client
+}
+
+fn header_str(
+  headers: &HeaderMap,
+  name: header::HeaderName,
+) -> Option<String> {
+  headers
+    .get(name)
+    .and_then(|v| v.to_str().ok())
+    .map(|v| v.to_string())
+}
+
+/// CLFの引用符内に書けるように制御文字と`"`と`\`をエスケープする
+fn escape_clf(s: &str) -> String {
+  let mut escaped = String::with_capacity(s.len());
+  for c in s.chars() {
+    match c {
+      '"' | '\\' => {
+        escaped.push('\\');
+        escaped.push(c);
+      }
+      c if c.is_control() => {
+        let _ =
+          escaped.write_fmt(format_args!("\\x{:02x}", c as u32));
+      }
+      c => escaped.push(c),
+    }
+  }
+  escaped
+}
+
+/// リクエストとレスポンスの内容をアクセスログに記録するミドルウェア
+pub async fn access_log(req: Request, next: Next) -> Response {
+  let Some(file) = ACCESS_LOG.get() else {
+    return next.run(req).await;
+  };
+  let start = Instant::now();
+  let config = crate::config::get();
+  let config = &config.access_log;
+
+  let method = req.method().to_string();
+  let uri =
+    redact_uri(&req.uri().to_string(), &config.redact_params);
+  let version = format!("{:?}", req.version());
+  let referer = header_str(req.headers(), header::REFERER)
+    .map(|v| redact_uri(&v, &config.redact_params));
+  let user_agent = header_str(req.headers(), header::USER_AGENT);
+  let peer = req
+    .extensions()
+    .get::<ConnectInfo<PeerAddr>>()
+    .map(|ConnectInfo(peer)| *peer);
+  let client =
+    client_ip(peer, req.headers(), &config.trusted_proxies);
+
+  let request_id = crate::request_id::current();
+  let res = next.run(req).await;
+
+  let latency = start.elapsed();
+  let status = res.status().as_u16();
+  let bytes = res
+    .headers()
+    .get(header::CONTENT_LENGTH)
+    .and_then(|v| v.to_str().ok())
+    .and_then(|v| v.parse::<u64>().ok())
+    .or(res.body().size_hint().exact());
+  let mut line = match config.format {
+    AccessLogFormat::Combined => format!(
+      "{client} - - [{time}] \"{method} {uri} {version}\" \
+        {status} {bytes} \"{referer}\" \"{user_agent}\" {latency} \
+        {request_id}",
+      client = client
+        .map(|ip| ip.to_string())
+        .unwrap_or_else(|| "-".into()),
+      time = Local::now().format("%d/%b/%Y:%H:%M:%S %z"),
+      uri = escape_clf(&uri),
+      bytes = bytes
+        .map(|b| b.to_string())
+        .unwrap_or_else(|| "-".into()),
+      referer = escape_clf(referer.as_deref().unwrap_or("-")),
+      user_agent =
+        escape_clf(user_agent.as_deref().unwrap_or("-")),
+      latency = latency.as_micros(),
+      request_id = request_id.as_deref().unwrap_or("-"),
+    ),
+    AccessLogFormat::Json => serde_json::json!({
+      "time": Local::now().to_rfc3339(),
+      "client": client,
+      "method": method,
+      "uri": uri,
+      "version": version,
+      "status": status,
+      "bytes": bytes,
+      "referer": referer,
+      "user_agent": user_agent,
+      "latency_us": latency.as_micros() as u64,
+      "request_id": request_id,
+    })
+    .to_string(),
+  };
+  line.push('\n');
+  if let Err(e) = file.lock().write_line(line.as_bytes()) {
+    log::error!("access log write error: {e}");
+  }
+  res
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const PROXY: &str = "10.0.0.1";
+
+  fn client(peer: &str, forwarded: &[&str]) -> Option<IpAddr> {
+    let mut headers = HeaderMap::new();
+    for value in forwarded {
+      headers.append("x-forwarded-for", value.parse().unwrap());
+    }
+    let peer = match peer {
+      "unix" => PeerAddr::Unix,
+      ip => PeerAddr::Tcp(
+        (ip.parse::<IpAddr>().unwrap(), 443).into(),
+      ),
+    };
+    client_ip(Some(peer), &headers, &[PROXY.parse().unwrap()])
+  }
+
+  fn ip(s: &str) -> Option<IpAddr> {
+    Some(s.parse().unwrap())
+  }
+
+  #[test]
+  fn forwarded_for_is_only_read_from_trusted_proxies() {
+    assert_eq!(
+      client("192.0.2.1", &["198.51.100.1"]),
+      ip("192.0.2.1")
+    );
+    assert_eq!(
+      client(PROXY, &["198.51.100.1"]),
+      ip("198.51.100.1")
+    );
+    assert_eq!(client("unix", &[]), None);
+    assert_eq!(client(PROXY, &[]), ip(PROXY));
+  }
+
+  #[test]
+  fn the_rightmost_untrusted_address_is_the_client() {
+    // 左側はクライアントが自由に書ける
+    assert_eq!(
+      client(PROXY, &["203.0.113.9, 198.51.100.1, 10.0.0.1"]),
+      ip("198.51.100.1")
+    );
+    assert_eq!(
+      client("unix", &["203.0.113.9", "198.51.100.1"]),
+      ip("198.51.100.1")
+    );
+    // 全て信用するプロキシなら最も左のもの
+    assert_eq!(client("unix", &["10.0.0.1"]), ip(PROXY));
+  }
+
+  #[test]
+  fn invalid_entries_stop_the_walk() {
+    assert_eq!(
+      client(PROXY, &["203.0.113.9, unknown, 10.0.0.1"]),
+      ip(PROXY)
+    );
+    assert_eq!(
+      client(PROXY, &["203.0.113.9, unknown"]),
+      ip(PROXY)
+    );
+    assert_eq!(client("unix", &["203.0.113.9, unknown"]), None);
+  }
+
+  #[test]
+  fn listed_query_values_are_redacted() {
+    let params = ["token".to_string(), "password".to_string()];
+    assert_eq!(
+      redact_query("a=1&token=secret&password=&b", &params),
+      "a=1&token=REDACTED&password=REDACTED&b"
+    );
+    assert_eq!(
+      redact_uri("/mainte?token=x", &params),
+      "/mainte?token=REDACTED"
+    );
+    assert_eq!(redact_uri("/mainte", &params), "/mainte");
+    // 名前をエンコードしても伏せる
+    assert_eq!(
+      redact_query(
+        "pass%77ord=secret&to+ken=x&a%3D1=2",
+        &["password".into(), "to ken".into(),]
+      ),
+      "password=REDACTED&to ken=REDACTED&a%3D1=2"
+    );
+    assert_eq!(
+      redact_query("token%3Dsecret&token2=1", &params),
+      "token=REDACTED&token2=1"
+    );
+  }
+
+  #[test]
+  fn clf_fields_are_escaped() {
+    assert_eq!(escape_clf("curl/8.0"), "curl/8.0");
+    assert_eq!(
+      escape_clf("a\"b\\c\nd\u{7f}"),
+      "a\\\"b\\\\c\\x0ad\\x7f"
+    );
+  }
+}
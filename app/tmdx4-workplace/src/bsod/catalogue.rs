@@ -0,0 +1,228 @@
+//! ステータスコードごとのBSODの文言
+
+use axum::http::StatusCode;
+
+/// ステータスコードごとの本文と対処の文言
+pub(super) type BsodEntry =
+  (&'static [&'static str], Option<&'static str>);
+
+pub(super) const BSOD_DEFAULT_TODO: &str =
+  "任意のｱﾄﾞﾚｽを入力するか、前のページにお戻りください.";
+const BSOD_RETRY_TODO: &str =
+  "しばらく時間をおいてから再度お試しください.";
+
+/// 4xx・5xxのステータスコードの文言
+fn catalogue(status_code: StatusCode) -> Option<BsodEntry> {
+  let entry: BsodEntry = match status_code.as_u16() {
+    400 => (
+      &[
+        "要求が不正の為処理が不能・もしくは実行に不適の為、正常なリクエストの生成が出来ませんでした。",
+        "TIPS: クエリパラメータ・フォーム内容をご確認ください。",
+      ],
+      None,
+    ),
+    401 => (
+      &[
+        "この操作には認証が必要です。",
+        "TIPS: ユーザ名とパスワードをご確認ください。",
+      ],
+      None,
+    ),
+    402 => (&["この操作には支払いが必要です。"], None),
+    403 => (
+      &[
+        "クライアントはサーバの該当コンテンツへのアクセス権がありません。",
+        "TIPS: URIを確認してください。",
+      ],
+      None,
+    ),
+    404 => (
+      &[
+        "要求されたリクエストはURIが誤っているか、リソース自体がサーバに存在しておりません。",
+        "TIPS: URIを確認し、スペルミスおよびワードチョイスや数値のミスが無いかをご確認ください。",
+      ],
+      None,
+    ),
+    405 => (
+      &[
+        "要求されたメソッドはこのリソースでは使用できません。",
+        "TIPS: フォームの送信先・送信方法をご確認ください。",
+      ],
+      None,
+    ),
+    406 => (
+      &["要求された形式で応答を返すことが出来ませんでした。"],
+      None,
+    ),
+    407 => (&["プロキシでの認証が必要です。"], None),
+    408 => (
+      &["リクエストの受信が時間内に完了しませんでした。"],
+      Some(BSOD_RETRY_TODO),
+    ),
+    409 => (
+      &[
+        "要求はリソースの現在の状態と競合した為、処理できませんでした。",
+        "TIPS: ページを再読み込みしてから再度お試しください。",
+      ],
+      None,
+    ),
+    410 => (
+      &[
+        "要求されたリソースは削除され、今後も利用できません。",
+        "TIPS: ブックマーク等に登録されている場合は削除してください。",
+      ],
+      None,
+    ),
+    411 => (&["リクエストにContent-Lengthが必要です。"], None),
+    412 => {
+      (&["リクエストの前提条件が満たされませんでした。"], None)
+    }
+    413 => (
+      &[
+        "リクエストの内容が大きすぎる為、処理できませんでした。",
+        "TIPS: 送信する内容を減らしてください。",
+      ],
+      None,
+    ),
+    414 => (
+      &[
+        "URIが長すぎる為、処理できませんでした。",
+        "TIPS: クエリパラメータをご確認ください。",
+      ],
+      None,
+    ),
+    415 => {
+      (&["リクエストの形式はサポートされておりません。"], None)
+    }
+    416 => (&["要求された範囲は取得できません。"], None),
+    417 => (&["Expectヘッダの要求に応えられません。"], None),
+    418 => (
+      &[
+        "当サーバはティーポットの為、コーヒーを淹れることが出来ません。",
+      ],
+      None,
+    ),
+    421 => {
+      (&["リクエストは応答できないサーバに送られました。"], None)
+    }
+    422 => (
+      &[
+        "リクエストの内容を処理できませんでした。",
+        "TIPS: フォーム内容をご確認ください。",
+      ],
+      None,
+    ),
+    423 => (&["要求されたリソースはロックされています。"], None),
+    424 => (
+      &["依存する要求が失敗した為、処理できませんでした。"],
+      None,
+    ),
+    425 => (
+      &["リプレイされる恐れのあるリクエストは処理できません。"],
+      Some(BSOD_RETRY_TODO),
+    ),
+    426 => (&["プロトコルのアップグレードが必要です。"], None),
+    428 => (&["リクエストには条件の指定が必要です。"], None),
+    429 => (
+      &[
+        "短時間に多数のリクエストが送られた為、処理を制限しています。",
+      ],
+      Some(BSOD_RETRY_TODO),
+    ),
+    431 => (
+      &[
+        "リクエストヘッダが大きすぎる為、処理できませんでした。",
+      ],
+      None,
+    ),
+    451 => {
+      (&["法的な理由によりこのリソースは提供できません。"], None)
+    }
+    500 => (
+      &[
+        "サーバ内部でエラーが発生した為、リクエストを処理できませんでした。",
+        "この問題は管理者に記録されています。",
+      ],
+      Some(BSOD_RETRY_TODO),
+    ),
+    501 => {
+      (&["要求された機能はサーバに実装されておりません。"], None)
+    }
+    502 => (
+      &["上流のサーバから不正な応答を受け取りました。"],
+      Some(BSOD_RETRY_TODO),
+    ),
+    503 => (
+      &[
+        "サーバは現在リクエストを処理できません。",
+        "メンテナンス中か、過負荷の状態にあります。",
+      ],
+      Some(BSOD_RETRY_TODO),
+    ),
+    504 => (
+      &["上流のサーバからの応答が時間内にありませんでした。"],
+      Some(BSOD_RETRY_TODO),
+    ),
+    505 => (
+      &[
+        "リクエストのHTTPバージョンはサポートされておりません。",
+      ],
+      None,
+    ),
+    506 => (&["サーバの内容交渉の設定に誤りがあります。"], None),
+    507 => (
+      &[
+        "サーバの記憶領域が不足している為、処理できませんでした。",
+      ],
+      Some(BSOD_RETRY_TODO),
+    ),
+    508 => {
+      (&["処理中に無限ループを検出した為、中断しました。"], None)
+    }
+    510 => {
+      (&["リクエストの処理にはさらなる拡張が必要です。"], None)
+    }
+    511 => (&["ネットワークへの認証が必要です。"], None),
+    _ => return None,
+  };
+  Some(entry)
+}
+
+/// カタログに無いステータスコードの文言(クラスごと)
+fn fallback(status_code: StatusCode) -> BsodEntry {
+  if status_code.is_server_error() {
+    (
+      &[
+        "サーバでエラーが発生した為、リクエストを処理できませんでした。",
+      ],
+      Some(BSOD_RETRY_TODO),
+    )
+  } else {
+    (
+      &[
+        "リクエストを処理できませんでした。",
+        "TIPS: URI・クエリパラメータ・フォーム内容をご確認ください。",
+      ],
+      None,
+    )
+  }
+}
+
+/// `404 NOT FOUND`のような見出し
+pub(super) fn title(status_code: StatusCode) -> String {
+  match status_code.canonical_reason() {
+    Some(reason) => {
+      format!(
+        "{} {}",
+        status_code.as_str(),
+        reason.to_uppercase()
+      )
+    }
+    None => format!("{} ERROR", status_code.as_str()),
+  }
+}
+
+/// ステータスコードの文言(カタログに無ければクラスごとの文言)
+pub(super) fn entry(status_code: StatusCode) -> BsodEntry {
+  catalogue(status_code).unwrap_or_else(|| fallback(status_code))
+}
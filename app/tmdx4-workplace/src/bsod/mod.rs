@@ -1,174 +1,414 @@
-use std::borrow::Cow;
+//! エラーページ(BSOD)の実装
+//!
+//! ハンドラはエラーを[`Bsod`]で返し、応答の形式は要求元に合わせて選ぶ。
+//! ブラウザにはBSODのHTML、JSONを好むクライアントにはRFC 9457の
+//! `application/problem+json`、curl等にはプレーンテキストを返す
 
+use std::{any::Any, borrow::Cow};
+
+use askama::Template;
 use axum::{
-  http::StatusCode,
-  response::{Html, IntoResponse},
+  extract::Request,
+  http::{HeaderMap, HeaderValue, StatusCode, header},
+  middleware::Next,
+  response::{Html, IntoResponse, Response},
 };
+use serde::Serialize;
+
+use crate::html::{Escaped, Trusted};
+
+mod catalogue;
 
-const BSOD_STRING: &'static [&'static [Option<(
-  &'static str,
-  &'static [&'static str],
-  Option<&'static str>,
-)>]] = &[
-  &[],
-  &[],
-  &[],
-  &[],
-  &[
-    Some((
-      "400 BAD REQUEST",
-      &[
-        "要求が不正の為処理が不能・もしくは実行に不適の為、正常なリクエストの生成が出来ませんでした。",
-        "TIPS: クエリパラメータ・フォーム内容をご確認ください。",
-      ],
-      None,
-    )),
-    None,
-    None,
-    Some((
-      "403 FORBIDDEN",
-      &[
-        "クライアントはサーバの該当コンテンツへのアクセス権がありません。",
-        "TIPS: URIを確認してください。",
-      ],
-      None,
-    )),
-    Some((
-      "404 NOT FOUND",
-      &[
-        "要求されたリクエストはURIが誤っているか、リソース自体がサーバに存在しておりません。",
-        "TIPS: URIを確認し、スペルミスおよびワードチョイスや数値のミスが無いかをご確認ください。",
-      ],
-      None,
-    )),
-  ],
-  &[],
-];
-const BSOD_DEFAULT: [usize; 2] = [4, 3];
-const BSOD_DEFAULT_TODO: &'static str =
-  "任意のｱﾄﾞﾚｽを入力するか、前のページにお戻りください.";
-
-enum BsodString {
-  String(Cow<'static, str>),
-  Array(&'static [&'static str]),
+/// テキストで返すUser-Agent(Acceptで決まらない場合に使う)
+const TEXT_USER_AGENTS: &[&str] = &["curl/", "Wget/", "HTTPie/"];
+
+tokio::task_local! {
+  static FORMAT: Format;
 }
-impl std::fmt::Display for BsodString {
-  fn fmt(
-    &self,
-    f: &mut std::fmt::Formatter<'_>,
-  ) -> std::fmt::Result {
-    match self {
-      Self::String(s) => f.write_str(&s)?,
-      Self::Array(a) => {
-        for s in a.iter() {
-          f.write_str(s)?;
-          f.write_str("<br>")?;
-        }
+
+/// エラーの応答の形式
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Format {
+  Html,
+  Json,
+  Text,
+}
+impl Format {
+  /// リクエストヘッダから応答の形式を選ぶ
+  /// Acceptの品質値で決め、決まらなければUser-Agentで決める
+  fn preferred(headers: &HeaderMap) -> Self {
+    let user_agent = || {
+      let user_agent = headers
+        .get(header::USER_AGENT)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+      if TEXT_USER_AGENTS
+        .iter()
+        .any(|prefix| user_agent.starts_with(prefix))
+      {
+        Self::Text
+      } else {
+        Self::Html
       }
+    };
+    let Some(accept) =
+      headers.get(header::ACCEPT).and_then(|v| v.to_str().ok())
+    else {
+      return user_agent();
+    };
+    let html = quality(accept, "text/html");
+    let json = quality(accept, "application/problem+json")
+      .max(quality(accept, "application/json"));
+    let text = quality(accept, "text/plain");
+    if html == json && json == text {
+      user_agent()
+    } else if html >= json && html >= text {
+      Self::Html
+    } else if json >= text {
+      Self::Json
+    } else {
+      Self::Text
     }
-    Ok(())
   }
 }
 
-pub fn bsod(
+/// Acceptのうち`mime`に最も具体的に一致したメディア範囲の品質値(千分率)
+fn quality(accept: &str, mime: &str) -> u16 {
+  let (ty, _) = mime.split_once('/').unwrap_or((mime, ""));
+  accept
+    .split(',')
+    .filter_map(|range| {
+      let mut params = range.split(';');
+      let range = params.next()?.trim().to_ascii_lowercase();
+      let specificity = if range == mime {
+        2
+      } else if range.strip_suffix("/*") == Some(ty) {
+        1
+      } else if range == "*/*" {
+        0
+      } else {
+        return None;
+      };
+      let q = params
+        .filter_map(|param| param.trim().strip_prefix("q="))
+        .find_map(|q| q.trim().parse::<f32>().ok())
+        .unwrap_or(1.0)
+        .clamp(0.0, 1.0);
+      Some((specificity, (q * 1000.0) as u16))
+    })
+    .max_by_key(|(specificity, _)| *specificity)
+    .map(|(_, q)| q)
+    .unwrap_or(0)
+}
+
+/// エラーの応答を返す形式をリクエストから決め、ハンドラの実行中に保持する
+pub async fn negotiate(req: Request, next: Next) -> Response {
+  let format = Format::preferred(req.headers());
+  FORMAT.scope(format, next.run(req)).await
+}
+
+/// エラーの応答
+/// 文言を指定しなければステータスコードごとの既定の文言を使う
+#[derive(Debug)]
+pub struct Bsod {
   status_code: StatusCode,
-  error_msg: Option<Cow<'static, str>>,
-  todo_msg: Option<Cow<'static, str>>,
-) -> impl IntoResponse {
-  let ss = status_code.as_str().chars();
-  let (head, tail) = (
-    ss.clone().nth(0).unwrap(),
-    [ss.clone().nth(1).unwrap(), ss.clone().nth(2).unwrap()],
-  );
-  let head = head.to_digit(10).unwrap();
-  let tail = tail[0].to_digit(10).unwrap() * 10
-    + tail[1].to_digit(10).unwrap();
-  let head = head as usize;
-  let tail = tail as usize;
-  let (error_code, text, todo) =
-    BSOD_STRING.get(head).map(|s| s[tail]).flatten().unwrap_or(
-      BSOD_STRING[BSOD_DEFAULT[0]][BSOD_DEFAULT[1]].unwrap(),
+  text: Option<Cow<'static, str>>,
+  todo: Option<Cow<'static, str>>,
+}
+impl Bsod {
+  pub fn new(status_code: StatusCode) -> Self {
+    Self {
+      status_code,
+      text: None,
+      todo: None,
+    }
+  }
+
+  /// 本文を差し替える
+  pub fn text(
+    mut self,
+    text: impl Into<Cow<'static, str>>,
+  ) -> Self {
+    self.text = Some(text.into());
+    self
+  }
+
+  /// 右下の対処の案内を差し替える
+  pub fn todo(
+    mut self,
+    todo: impl Into<Cow<'static, str>>,
+  ) -> Self {
+    self.todo = Some(todo.into());
+    self
+  }
+
+  /// 本文の各行
+  fn lines(&self) -> Vec<&str> {
+    match &self.text {
+      Some(text) => vec![text],
+      None => catalogue::entry(self.status_code).0.to_vec(),
+    }
+  }
+
+  fn todo_or_default(&self) -> &str {
+    match &self.todo {
+      Some(todo) => todo,
+      None => catalogue::entry(self.status_code)
+        .1
+        .unwrap_or(catalogue::BSOD_DEFAULT_TODO),
+    }
+  }
+
+  fn page(&self) -> BsodPage<'_> {
+    let request_id = crate::request_id::current();
+    let text = match &self.text {
+      Some(text) => BsodString::String(Escaped(text)),
+      None => BsodString::Array(
+        catalogue::entry(self.status_code)
+          .0
+          .iter()
+          .copied()
+          .map(Trusted::constant)
+          .collect(),
+      ),
+    };
+    BsodPage {
+      nonce: crate::security_headers::nonce(),
+      error_code: Escaped(catalogue::title(self.status_code)),
+      text,
+      todo: Escaped(self.todo_or_default()),
+      request_id: request_id.map(Escaped),
+    }
+  }
+
+  fn problem(&self) -> Problem {
+    Problem {
+      kind: "about:blank",
+      title: self
+        .status_code
+        .canonical_reason()
+        .unwrap_or("Unknown Error"),
+      status: self.status_code.as_u16(),
+      detail: self.lines().join("\n"),
+      request_id: crate::request_id::current(),
+    }
+  }
+
+  fn plain_text(&self) -> String {
+    let mut text = format!(
+      "{}\n\n{}\n\n{}\n",
+      catalogue::title(self.status_code),
+      self.lines().join("\n"),
+      self.todo_or_default()
+    );
+    if let Some(request_id) = crate::request_id::current() {
+      text += &format!("request ID: {request_id}\n");
+    }
+    text
+  }
+}
+impl IntoResponse for Bsod {
+  fn into_response(self) -> Response {
+    let format =
+      FORMAT.try_with(|format| *format).unwrap_or(Format::Html);
+    let mut response = match format {
+      Format::Html => match self.page().render() {
+        Ok(html) => Html(html).into_response(),
+        Err(e) => {
+          log::error!("cannot render the bsod page: {e}");
+          self.plain_text().into_response()
+        }
+      },
+      Format::Json => {
+        match serde_json::to_string(&self.problem()) {
+          Ok(json) => (
+            [(header::CONTENT_TYPE, "application/problem+json")],
+            json,
+          )
+            .into_response(),
+          Err(e) => {
+            log::error!(
+              "cannot serialize the problem details: {e}"
+            );
+            self.plain_text().into_response()
+          }
+        }
+      }
+      Format::Text => self.plain_text().into_response(),
+    };
+    *response.status_mut() = self.status_code;
+    response.headers_mut().append(
+      header::VARY,
+      HeaderValue::from_static("Accept, User-Agent"),
     );
-  let todo = error_msg
-    .unwrap_or(todo.unwrap_or(BSOD_DEFAULT_TODO).into());
-  let text = todo_msg
-    .map(|v| BsodString::String(v))
-    .unwrap_or(BsodString::Array(text));
-  (
-    status_code,
-    Html(format!(
-      "<!doctype html>
-      <html lang='ja'>
-        <head>
-          <meta charset='utf-8'>
-          <meta name='viewport' content='width=device-width,initial-scale=1,minimum-scale=1'>
-          <title>404 NOT FOUND</title>
-          <style>
-            * {{
-              margin: 0;
-              padding: 0;
-              border: none;
-              box-sizing: border-box;
-              color: white;
-              white-space: wrap;
-              overflow-wrap: break-word;
-              word-break: break-all;
-              font-family: 'MS UI Gothic';
-            }}
-
-            html {{
-              width: 100%;
-              height: 100%;
-              background-color: darkblue;
-
-              display: flex;
-              flex-flow: column;
-              justify-content: center;
-              align-items: center;
-
-              > body {{
-                min-width: 18rem;
-                max-width: 96rem;
-                display: flex;
-                flex-flow: column;
-                justify-content: center;
-                align-items: center;
-
-                > div#title {{
-                  width: fit-content;
-                  padding-inline: 0.5rem;
-                  background-color: lightgray;
-                  > h1 {{
-                    font-size: 1rem;
-                    font-weight: bolder;
-                    color: darkblue;
-                  }}
-                }}
-
-                > div#message {{
-                  margin-top: 1rem;
-                  width: 100%;
-
-                  > div.align-right {{
-                    width: 100%;
-                    text-align: right;
-                  
-                  }}
-                }}
-              }}
-            }}
-          </style>
-        </head>
-        <body>
-          <div id='title'><h1>{error_code}</h1></div>
-          <div id='message'>
-            {text}
-            <br>
-            <div class='align-right'>{todo}</div>
-          </div>
-        </body>
-      </html>
-      ", 
-    )),
-  )
+    response
+  }
+}
+
+/// RFC 9457のProblem Details
+#[derive(Serialize)]
+struct Problem {
+  #[serde(rename = "type")]
+  kind: &'static str,
+  title: &'static str,
+  status: u16,
+  detail: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  request_id: Option<String>,
+}
+
+/// 呼び出し側から渡された文言はエスケープし、定数の文言はそのまま埋め込む
+enum BsodString<'a> {
+  String(Escaped<&'a str>),
+  Array(Vec<Trusted<&'static str>>),
+}
+
+#[derive(Template)]
+#[template(path = "bsod.html")]
+struct BsodPage<'a> {
+  nonce: String,
+  error_code: Escaped<String>,
+  text: BsodString<'a>,
+  todo: Escaped<&'a str>,
+  request_id: Option<Escaped<String>>,
+}
+
+/// ハンドラがパニックした時に接続を切る代わりに500のBSODを返す
+pub fn panic_response(
+  panic: Box<dyn Any + Send + 'static>,
+) -> Response {
+  let message = panic
+    .downcast_ref::<&str>()
+    .copied()
+    .or_else(|| {
+      panic.downcast_ref::<String>().map(String::as_str)
+    })
+    .unwrap_or("(non-string payload)");
+  log::error!("handler panicked: {message}");
+  Bsod::new(StatusCode::INTERNAL_SERVER_ERROR).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn hostile_messages_are_escaped() {
+    let hostile = "</div><script>alert(1)</script>";
+    let html = Bsod::new(StatusCode::BAD_REQUEST)
+      .text(hostile)
+      .todo(hostile)
+      .page()
+      .render()
+      .unwrap();
+    assert!(!html.contains("<script>"));
+    assert_eq!(
+      html.matches("&lt;/div&gt;&lt;script&gt;").count(),
+      2
+    );
+  }
+
+  #[test]
+  fn constant_messages_keep_markup() {
+    let html =
+      Bsod::new(StatusCode::NOT_FOUND).page().render().unwrap();
+    assert!(html.contains("ご確認ください。<br>"));
+  }
+
+  #[test]
+  fn every_error_status_has_a_page() {
+    for code in 400..600 {
+      let status = StatusCode::from_u16(code).unwrap();
+      let html = Bsod::new(status).page().render().unwrap();
+      assert!(
+        html.contains(&format!("<title>{code} ")),
+        "{code} has no title"
+      );
+    }
+  }
+
+  #[test]
+  fn titles_follow_the_status() {
+    assert_eq!(
+      catalogue::title(StatusCode::NOT_FOUND),
+      "404 NOT FOUND"
+    );
+    assert_eq!(
+      catalogue::title(StatusCode::TOO_MANY_REQUESTS),
+      "429 TOO MANY REQUESTS"
+    );
+    assert_eq!(
+      catalogue::title(StatusCode::from_u16(599).unwrap()),
+      "599 ERROR"
+    );
+  }
+
+  fn preferred(
+    accept: Option<&str>,
+    user_agent: &str,
+  ) -> Format {
+    let mut headers = HeaderMap::new();
+    if let Some(accept) = accept {
+      headers.insert(header::ACCEPT, accept.parse().unwrap());
+    }
+    headers
+      .insert(header::USER_AGENT, user_agent.parse().unwrap());
+    Format::preferred(&headers)
+  }
+
+  #[test]
+  fn browsers_get_html() {
+    let accept = "text/html,application/xhtml+xml,\
+      application/xml;q=0.9,*/*;q=0.8";
+    assert_eq!(
+      preferred(Some(accept), "Mozilla/5.0"),
+      Format::Html
+    );
+    assert_eq!(preferred(None, "Mozilla/5.0"), Format::Html);
+  }
+
+  #[test]
+  fn json_clients_get_problem_details() {
+    assert_eq!(
+      preferred(Some("application/json"), "feed-reader"),
+      Format::Json
+    );
+    assert_eq!(
+      preferred(
+        Some("text/html;q=0.5, application/problem+json"),
+        "Mozilla/5.0"
+      ),
+      Format::Json
+    );
+  }
+
+  #[test]
+  fn curl_gets_plain_text() {
+    assert_eq!(
+      preferred(Some("*/*"), "curl/8.5.0"),
+      Format::Text
+    );
+    assert_eq!(preferred(None, "Wget/1.21"), Format::Text);
+    assert_eq!(
+      preferred(Some("text/html"), "curl/8.5.0"),
+      Format::Html
+    );
+  }
+
+  #[test]
+  fn problem_details_follow_rfc9457() {
+    let json = serde_json::to_value(
+      Bsod::new(StatusCode::GONE).text("deleted").problem(),
+    )
+    .unwrap();
+    assert_eq!(
+      json,
+      serde_json::json!({
+        "type": "about:blank",
+        "title": "Gone",
+        "status": 410,
+        "detail": "deleted",
+      })
+    );
+  }
 }
@@ -0,0 +1,294 @@
+//! レスポンスの圧縮とキャッシュ制御の実装
+//!
+//! HTML・CSS・JSONはgzipもしくはbrotliで圧縮し、
+//! `Cache-Control`はパスの前方一致で選んだ設定に従って付ける。
+//! ハンドラが付けたETagは条件付きリクエストの判定に使い、一致すれば304を返す
+
+use std::collections::BTreeMap;
+
+use axum::{
+  extract::Request,
+  http::{
+    Extensions, HeaderMap, HeaderValue, Method, StatusCode,
+    Version, header,
+  },
+  middleware::Next,
+  response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use tower_http::compression::{DefaultPredicate, Predicate};
+
+/// 圧縮の対象とするContent-Type
+const COMPRESSIBLE_TYPES: &[&str] = &[
+  "text/html",
+  "text/css",
+  "application/json",
+  "application/problem+json",
+];
+
+/// 圧縮とキャッシュ制御についてのコンフィグ
+#[derive(Deserialize, Serialize, Clone)]
+pub struct CachingConfig {
+  /// HTML・CSS・JSONのレスポンスを圧縮するか
+  pub compression: bool,
+
+  /// どの`routes`にも一致しないパスに付ける`Cache-Control`
+  pub default_cache_control: String,
+
+  /// パスの前方一致で付ける`Cache-Control`(最も長く一致したものを使う)
+  pub routes: BTreeMap<String, String>,
+}
+impl Default for CachingConfig {
+  fn default() -> Self {
+    Self {
+      compression: true,
+      default_cache_control: "no-cache".into(),
+      routes: BTreeMap::from([(
+        "/mainte".into(),
+        "no-store".into(),
+      )]),
+    }
+  }
+}
+impl CachingConfig {
+  /// コンフィグの内容を検証し、見つかった問題を`problems`に追加する
+  pub fn validate(&self, problems: &mut Vec<String>) {
+    if HeaderValue::try_from(self.default_cache_control.as_str())
+      .is_err()
+    {
+      problems.push(
+        "caching.default_cache_control: ヘッダの値に使えません"
+          .into(),
+      );
+    }
+    for (prefix, value) in &self.routes {
+      if !prefix.starts_with('/') {
+        problems.push(format!(
+          "caching.routes.{prefix}: パスの接頭辞は'/'で始めてください"
+        ));
+      }
+      if HeaderValue::try_from(value.as_str()).is_err() {
+        problems.push(format!(
+          "caching.routes.{prefix}: ヘッダの値に使えません"
+        ));
+      }
+    }
+  }
+
+  fn cache_control(&self, path: &str) -> &str {
+    self
+      .routes
+      .iter()
+      .filter(|(prefix, _)| path.starts_with(prefix.as_str()))
+      .max_by_key(|(prefix, _)| prefix.len())
+      .map(|(_, value)| value)
+      .unwrap_or(&self.default_cache_control)
+  }
+}
+
+/// 圧縮するレスポンスの判定
+pub fn compress_when() -> impl Predicate {
+  DefaultPredicate::new().and(
+    |_: StatusCode,
+     _: Version,
+     headers: &HeaderMap,
+     _: &Extensions| {
+      crate::config::get().caching.compression
+        && headers
+          .get(header::CONTENT_TYPE)
+          .and_then(|v| v.to_str().ok())
+          .and_then(|v| v.split(';').next())
+          .is_some_and(|v| {
+            COMPRESSIBLE_TYPES.contains(&v.trim())
+          })
+    },
+  )
+}
+
+/// `If-None-Match`のいずれかがETagと一致するか(弱い比較)
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+  let opaque = |tag: &str| {
+    let tag = tag.trim();
+    tag.strip_prefix("W/").unwrap_or(tag).to_string()
+  };
+  let etag = opaque(etag);
+  if_none_match
+    .split(',')
+    .any(|tag| tag.trim() == "*" || opaque(tag) == etag)
+}
+
+/// `Cache-Control`を付け、ETagが一致する条件付きリクエストには304を返す
+/// 圧縮したものは別の表現なので、符号化方式をETagに含める
+pub async fn caching(req: Request, next: Next) -> Response {
+  let config = crate::config::get();
+  let cache_control =
+    config.caching.cache_control(req.uri().path());
+  let conditional =
+    matches!(*req.method(), Method::GET | Method::HEAD);
+  let if_none_match = req
+    .headers()
+    .get(header::IF_NONE_MATCH)
+    .and_then(|v| v.to_str().ok())
+    .map(str::to_string);
+  let mut response = next.run(req).await;
+  let headers = response.headers_mut();
+  if !headers.contains_key(header::CACHE_CONTROL)
+    && let Ok(value) = HeaderValue::try_from(cache_control)
+  {
+    headers.insert(header::CACHE_CONTROL, value);
+  }
+  tag_encoding(headers);
+  match if_none_match {
+    Some(if_none_match) if conditional => {
+      not_modified(response, &if_none_match)
+    }
+    _ => response,
+  }
+}
+
+/// 圧縮したレスポンスのETagに符号化方式を付け足す
+fn tag_encoding(headers: &mut HeaderMap) {
+  if let Some(encoding) = headers
+    .get(header::CONTENT_ENCODING)
+    .and_then(|v| v.to_str().ok())
+    && let Some(etag) =
+      headers.get(header::ETAG).and_then(|v| v.to_str().ok())
+    && let Ok(etag) = HeaderValue::try_from(format!(
+      "{}-{encoding}\"",
+      etag.trim_end_matches('"')
+    ))
+  {
+    headers.insert(header::ETAG, etag);
+  }
+}
+
+/// 200のレスポンスのETagが`If-None-Match`と一致すれば304に置き換える
+/// クエリで変えた表示設定はキャッシュ済みのページと同じETagになりうるので、
+/// `Set-Cookie`も304に残してクッキーを更新させる
+fn not_modified(
+  response: Response,
+  if_none_match: &str,
+) -> Response {
+  let matches = response.status() == StatusCode::OK
+    && response
+      .headers()
+      .get(header::ETAG)
+      .and_then(|v| v.to_str().ok())
+      .is_some_and(|etag| etag_matches(if_none_match, etag));
+  if !matches {
+    return response;
+  }
+  let mut not_modified =
+    StatusCode::NOT_MODIFIED.into_response();
+  for name in [
+    header::ETAG,
+    header::CACHE_CONTROL,
+    header::VARY,
+    header::CONTENT_LOCATION,
+    header::EXPIRES,
+    header::SET_COOKIE,
+  ] {
+    for value in response.headers().get_all(&name) {
+      not_modified.headers_mut().append(&name, value.clone());
+    }
+  }
+  not_modified
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn page(etag: &str, encoding: Option<&str>) -> Response {
+    let mut response = "<html></html>".into_response();
+    let headers = response.headers_mut();
+    headers.insert(
+      header::ETAG,
+      HeaderValue::from_str(etag).unwrap(),
+    );
+    headers
+      .insert(header::VARY, HeaderValue::from_static("Cookie"));
+    if let Some(encoding) = encoding {
+      headers.insert(
+        header::CONTENT_ENCODING,
+        HeaderValue::from_str(encoding).unwrap(),
+      );
+    }
+    tag_encoding(response.headers_mut());
+    response
+  }
+
+  #[test]
+  fn etags_are_compared_weakly() {
+    assert!(etag_matches("W/\"abc\"", "W/\"abc\""));
+    assert!(etag_matches("\"abc\"", "W/\"abc\""));
+    assert!(etag_matches("\"x\", W/\"abc\"", "\"abc\""));
+    assert!(etag_matches("*", "\"abc\""));
+    assert!(!etag_matches("W/\"abd\"", "W/\"abc\""));
+  }
+
+  #[test]
+  fn compressed_responses_have_their_own_etag() {
+    let response = page("W/\"abc\"", Some("gzip"));
+    assert_eq!(
+      response.headers()[header::ETAG],
+      "W/\"abc-gzip\""
+    );
+    let response = page("W/\"abc\"", None);
+    assert_eq!(response.headers()[header::ETAG], "W/\"abc\"");
+  }
+
+  #[test]
+  fn matching_requests_get_304() {
+    let response = not_modified(
+      page("W/\"abc\"", Some("br")),
+      "W/\"abc-br\"",
+    );
+    assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+    assert_eq!(response.headers()[header::ETAG], "W/\"abc-br\"");
+    assert_eq!(response.headers()[header::VARY], "Cookie");
+    assert!(
+      !response.headers().contains_key(header::CONTENT_TYPE)
+    );
+
+    // 符号化方式が違えば別の表現
+    let response = not_modified(
+      page("W/\"abc\"", Some("br")),
+      "W/\"abc-gzip\"",
+    );
+    assert_eq!(response.status(), StatusCode::OK);
+  }
+
+  #[test]
+  fn cookies_are_kept_on_304() {
+    let mut response = page("W/\"abc\"", None);
+    response.headers_mut().insert(
+      header::SET_COOKIE,
+      HeaderValue::from_static("prefs=x.y; Path=/"),
+    );
+    let response = not_modified(response, "W/\"abc\"");
+    assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+    assert_eq!(
+      response.headers()[header::SET_COOKIE],
+      "prefs=x.y; Path=/"
+    );
+  }
+
+  #[test]
+  fn only_ok_responses_become_304() {
+    let mut response = page("\"abc\"", None);
+    *response.status_mut() = StatusCode::NOT_FOUND;
+    let response = not_modified(response, "\"abc\"");
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+  }
+
+  #[test]
+  fn the_longest_route_is_used() {
+    let config = CachingConfig::default();
+    assert_eq!(
+      config.cache_control("/mainte/users"),
+      "no-store"
+    );
+    assert_eq!(config.cache_control("/"), "no-cache");
+  }
+}
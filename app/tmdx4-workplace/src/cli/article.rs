@@ -0,0 +1,84 @@
+//! 記事の管理コマンド
+
+use std::path::{Path, PathBuf};
+
+use clap::Subcommand;
+
+use crate::service::article::{ArticleID, ArticleService};
+
+#[derive(Subcommand)]
+pub enum ArticleCommand {
+  /// Markdownファイルから記事を取り込む
+  /// (タイトル省略時は先頭の`# `見出しをタイトルとする)
+  Import {
+    file: PathBuf,
+    #[arg(long)]
+    title: Option<String>,
+  },
+
+  /// 記事をMarkdownとして書き出す(出力先省略時は標準出力)
+  Export {
+    id: ArticleID,
+    #[arg(long, short)]
+    output: Option<PathBuf>,
+  },
+
+  /// 記事を一覧表示する
+  List,
+}
+
+/// Markdownの本文からタイトルと本文を切り分ける
+fn split_title(
+  src: &str,
+  title: Option<String>,
+) -> Option<(String, String)> {
+  if let Some(title) = title {
+    return Some((title, src.to_string()));
+  }
+  let src = src.trim_start();
+  let (head, body) = src.split_once('\n').unwrap_or((src, ""));
+  let title = head.strip_prefix("# ")?.trim();
+  Some((title.to_string(), body.trim_start().to_string()))
+}
+
+pub fn run(
+  command: ArticleCommand,
+  config_path: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+  crate::config::init(config_path)?;
+  let config = crate::config::get();
+  let config = &config.service.articles;
+  let mut service = ArticleService::load(config)?;
+  match command {
+    ArticleCommand::Import { file, title } => {
+      let src = std::fs::read_to_string(&file)?;
+      let Some((title, body)) = split_title(&src, title) else {
+        return Err(Box::from(
+          "タイトルが見つかりません。--titleで指定してください",
+        ));
+      };
+      let aid = service.post(title, body)?.id();
+      service.save(config)?;
+      println!("{aid}");
+    }
+    ArticleCommand::Export { id, output } => {
+      let Some(article) = service.request(&id) else {
+        return Err(Box::from("記事が存在しません"));
+      };
+      let markdown =
+        format!("# {}\n\n{}", article.title(), article.body());
+      match output {
+        Some(path) => std::fs::write(path, markdown)?,
+        None => print!("{markdown}"),
+      }
+    }
+    ArticleCommand::List => {
+      let mut articles = service.iter().collect::<Vec<_>>();
+      articles.sort_by_key(|article| article.id());
+      for article in articles {
+        println!("{}\t{}", article.id(), article.title());
+      }
+    }
+  }
+  Ok(())
+}
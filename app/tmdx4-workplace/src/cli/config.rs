@@ -0,0 +1,39 @@
+//! コンフィグの確認コマンド
+
+use std::path::Path;
+
+use clap::Subcommand;
+
+use crate::config::{Config, ConfigError};
+
+#[derive(Subcommand)]
+pub enum ConfigCommand {
+  /// コンフィグを読み込み、検証で見つかった問題を全て表示する
+  Check,
+
+  /// 初期設定のコンフィグを標準出力に書き出す
+  PrintDefault,
+}
+
+pub fn run(
+  command: ConfigCommand,
+  config_path: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+  match command {
+    ConfigCommand::Check => {
+      let problems =
+        crate::config::load(config_path)?.validate();
+      if !problems.is_empty() {
+        return Err(Box::from(ConfigError::Invalid(problems)));
+      }
+      println!("{}: OK", config_path.display());
+    }
+    ConfigCommand::PrintDefault => {
+      println!(
+        "{}",
+        serde_json::to_string_pretty(&Config::default())?
+      );
+    }
+  }
+  Ok(())
+}
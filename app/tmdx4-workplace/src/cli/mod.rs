@@ -0,0 +1,66 @@
+//! コマンドラインからの管理操作の実装
+//!
+//! Web UIを介さずにSSH越しでスクリプトから操作できるようにする
+
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand};
+
+pub mod article;
+pub mod config;
+pub mod user;
+
+#[derive(Parser)]
+#[command(
+  version,
+  about = "ツナマヨの屋根裏部屋のサーバプログラム"
+)]
+pub struct Cli {
+  /// コンフィグファイルのパス
+  #[arg(
+    long,
+    short,
+    global = true,
+    env = crate::config::ENV_CONFIG_PATH,
+    default_value = crate::config::DEFAULT_CONFIG_PATH
+  )]
+  pub config: PathBuf,
+
+  #[command(subcommand)]
+  pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+  /// サーバを起動する(サブコマンド省略時の既定)
+  Serve,
+
+  /// メンテナンスページのユーザ管理
+  #[command(subcommand)]
+  User(user::UserCommand),
+
+  /// 記事の管理
+  #[command(subcommand)]
+  Article(article::ArticleCommand),
+
+  /// コンフィグの確認
+  #[command(subcommand)]
+  Config(config::ConfigCommand),
+}
+
+/// 標準入力からパスワードを一行読み込む
+/// 端末から実行されている場合は標準エラー出力にプロンプトを出す
+fn read_password(
+  prompt: &str,
+) -> Result<String, Box<dyn std::error::Error>> {
+  use std::io::{BufRead, IsTerminal, Write};
+  let stdin = std::io::stdin();
+  if stdin.is_terminal() {
+    let mut stderr = std::io::stderr();
+    stderr.write_all(prompt.as_bytes())?;
+    stderr.flush()?;
+  }
+  let mut line = String::new();
+  stdin.lock().read_line(&mut line)?;
+  Ok(line.trim().to_string())
+}
@@ -0,0 +1,102 @@
+//! メンテナンスユーザの管理コマンド
+
+use std::path::Path;
+
+use clap::Subcommand;
+
+use crate::usersys::{UserData, UserIdent};
+
+#[derive(Subcommand)]
+pub enum UserCommand {
+  /// ユーザを追加する(パスワードは標準入力から読み込む)
+  Add { name: String },
+
+  /// 登録済みユーザの識別子を一覧表示する
+  List,
+
+  /// ユーザのパスワードを再設定する(パスワードは標準入力から読み込む)
+  Passwd { name: String },
+
+  /// ユーザを削除する
+  Delete { name: String },
+}
+
+/// 新しいパスワードを入力させ、長さと確認入力を検証する
+fn input_new_password()
+-> Result<String, Box<dyn std::error::Error>> {
+  let pswd_len_min =
+    crate::config::get().maintenance_page.pswd_len_min;
+  let pswd = super::read_password("新しいパスワード: ")?;
+  if pswd.len() < pswd_len_min {
+    return Err(Box::from(format!(
+      "パスワードの長さは{pswd_len_min}以上にしてください"
+    )));
+  }
+  if std::io::IsTerminal::is_terminal(&std::io::stdin())
+    && super::read_password("新しいパスワード(確認): ")? != pswd
+  {
+    return Err(Box::from("新旧のパスワードが一致しません"));
+  }
+  Ok(pswd)
+}
+
+pub fn run(
+  command: UserCommand,
+  config_path: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+  crate::config::init(config_path)?;
+  let config = crate::config::get();
+  let config = &config.maintenance_page;
+  match command {
+    UserCommand::Add { name } => {
+      if UserData::<()>::check_exist(
+        &name,
+        &config.usersys_config,
+      )? {
+        return Err(Box::from("ユーザ名が重複しています"));
+      }
+      let pswd = input_new_password()?;
+      UserData::new(&name, &pswd, (), &config.usersys_config)?
+        .save(&config.usersys_config)?;
+      println!("{}", UserIdent::generate(&name)?);
+    }
+    UserCommand::List => {
+      let default_ident =
+        UserIdent::generate(&config.initial_username)?
+          .to_string();
+      for ident in
+        UserData::<()>::list_idents(&config.usersys_config)?
+      {
+        if ident == default_ident {
+          println!("{ident}\t(initial user)");
+        } else {
+          println!("{ident}");
+        }
+      }
+    }
+    UserCommand::Passwd { name } => {
+      let pswd = input_new_password()?;
+      if !UserData::<()>::reset_password(
+        &name,
+        &pswd,
+        &config.usersys_config,
+      )? {
+        return Err(Box::from("ユーザが存在しません"));
+      }
+    }
+    UserCommand::Delete { name } => {
+      if !UserData::<()>::delete(&name, &config.usersys_config)?
+      {
+        return Err(Box::from("ユーザが存在しません"));
+      }
+      if UserData::<()>::list_idents(&config.usersys_config)?
+        .is_empty()
+      {
+        eprintln!(
+          "ユーザが存在しなくなった為、次回のサーバ起動時に初期ユーザが再生成されます"
+        );
+      }
+    }
+  }
+  Ok(())
+}
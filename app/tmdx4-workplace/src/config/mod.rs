@@ -0,0 +1,542 @@
+//! コンフィグの読み込みと検証
+//!
+//! 初期値 → コンフィグファイル → 環境変数 の順に重ねて読み込み、
+//! 検証で見つかった問題はまとめて報告する。
+//! SIGHUPもしくはファイルの更新で再読み込みするが、
+//! 待ち受けポート等の構造的な設定は再起動するまで反映しない
+
+use std::{
+  path::{Path, PathBuf},
+  sync::{Arc, OnceLock},
+  time::{Duration, SystemTime},
+};
+
+use parking_lot::RwLock;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+  access_log, caching, listener, logging, main_page, mainte,
+  preferences, security_headers, service, stylesheet, theme,
+  usersys,
+};
+
+/// コンフィグファイルの既定のパス
+pub const DEFAULT_CONFIG_PATH: &str = "config.json";
+
+/// 環境変数による上書きの接頭辞
+/// `TMDX4_MAINTENANCE_PAGE__PSWD_LEN_MIN=20` のように`__`で階層を区切る
+pub const ENV_PREFIX: &str = "TMDX4_";
+
+/// コンフィグファイルのパスを指定する環境変数(上書きの対象外)
+pub const ENV_CONFIG_PATH: &str = "TMDX4_CONFIG";
+
+#[derive(Deserialize, Serialize)]
+pub struct Config {
+  pub origin_time: DateTime<Utc>,
+  pub listen_port: u16,
+  /// 待ち受けの一覧(空なら`0.0.0.0:listen_port`のみ)
+  pub listeners: Vec<listener::ListenerConfig>,
+  pub hsts: listener::tls::HstsConfig,
+  pub security_headers: security_headers::SecurityHeadersConfig,
+  pub caching: caching::CachingConfig,
+  pub stylesheet: stylesheet::StylesheetConfig,
+  pub preferences: preferences::PreferencesConfig,
+  pub view_mode: main_page::ViewModeConfig,
+  pub themes: theme::ThemesConfig,
+  /// 終了時に処理中のリクエストを待つ秒数
+  pub shutdown_timeout_secs: u64,
+  pub log_file: String,
+  pub log_level: log::LevelFilter,
+  pub logging: logging::LoggingConfig,
+  pub access_log: access_log::AccessLogConfig,
+  pub maintenance_page: mainte::MaintePageConfig,
+  pub service: service::ServiceConfig,
+}
+impl Default for Config {
+  fn default() -> Self {
+    Self {
+      origin_time: DateTime::parse_from_rfc3339(
+        "2025-01-01T00:00:00.00Z",
+      )
+      .unwrap()
+      .into(),
+      listen_port: 8080,
+      listeners: Vec::new(),
+      hsts: listener::tls::HstsConfig::default(),
+      security_headers:
+        security_headers::SecurityHeadersConfig::default(),
+      caching: caching::CachingConfig::default(),
+      stylesheet: stylesheet::StylesheetConfig::default(),
+      preferences: preferences::PreferencesConfig::default(),
+      view_mode: main_page::ViewModeConfig::default(),
+      themes: theme::ThemesConfig::default(),
+      shutdown_timeout_secs: 30,
+      log_file: "tmdx4-workplace.log".into(),
+      log_level: if cfg!(debug_assertions) {
+        log::LevelFilter::Debug
+      } else {
+        log::LevelFilter::Info
+      },
+      logging: logging::LoggingConfig::default(),
+      access_log: access_log::AccessLogConfig::default(),
+      maintenance_page: mainte::MaintePageConfig::default(),
+      service: service::ServiceConfig::default(),
+    }
+  }
+}
+impl Config {
+  /// コンフィグの内容を検証し、見つかった問題を全て返す
+  pub fn validate(&self) -> Vec<String> {
+    let mut problems = Vec::new();
+    if self.listen_port == 0 {
+      problems.push(
+        "listen_port: 0は待ち受けポートに使えません".into(),
+      );
+    }
+    for (i, listener) in self.listeners.iter().enumerate() {
+      listener
+        .validate(&format!("listeners[{i}]"), &mut problems);
+    }
+    self.hsts.validate(&mut problems);
+    self.security_headers.validate(&mut problems);
+    self.caching.validate(&mut problems);
+    self.stylesheet.validate(&mut problems);
+    self.preferences.validate(&mut problems);
+    self.view_mode.validate(&mut problems);
+    self.themes.validate(&mut problems);
+    self.service.counter.validate(&mut problems);
+    self.service.announcements.validate(&mut problems);
+    for (key, file) in [
+      ("log_file", Some(&self.log_file)),
+      ("access_log.file", self.access_log.file.as_ref()),
+    ] {
+      if let Some(parent) = file
+        .and_then(|file| Path::new(file).parent())
+        .filter(|p| !p.as_os_str().is_empty())
+        && !parent.is_dir()
+      {
+        problems.push(format!(
+          "{key}: ディレクトリ{}がありません",
+          parent.display()
+        ));
+      }
+    }
+
+    let mainte = &self.maintenance_page;
+    if let Err(e) = mainte.usersys_config.init_argon2_param() {
+      problems.push(format!(
+        "maintenance_page.usersys_config: Argon2のパラメータが不正です: {e}"
+      ));
+    }
+    if mainte.pswd_len_min == 0 {
+      problems.push(
+        "maintenance_page.pswd_len_min: 1以上にしてください"
+          .into(),
+      );
+    }
+    if let Err(e) =
+      usersys::UserIdent::generate(&mainte.initial_username)
+    {
+      problems
+        .push(format!("maintenance_page.initial_username: {e}"));
+    }
+    for (key, path) in [
+      ("maintenance_page.password_dir", &mainte.password_dir),
+      (
+        "maintenance_page.usersys_config.sec_data_path",
+        &mainte.usersys_config.sec_data_path,
+      ),
+      (
+        "maintenance_page.usersys_config.user_data_path",
+        &mainte.usersys_config.user_data_path,
+      ),
+      (
+        "service.articles.article_rootpath",
+        &self.service.articles.article_rootpath,
+      ),
+    ] {
+      // 無ければ後で作られるので、あるのにディレクトリでない場合だけ問題とする
+      let path = Path::new(path);
+      if path.exists() && !path.is_dir() {
+        problems.push(format!(
+          "{key}: {}はディレクトリではありません",
+          path.display()
+        ));
+      }
+    }
+    if !Path::new(&self.service.assets.assets_rootpath).is_dir()
+    {
+      problems.push(format!(
+        "service.assets.assets_rootpath: ディレクトリ{}がありません",
+        self.service.assets.assets_rootpath
+      ));
+    }
+    for (key, rotation) in [
+      ("logging.rotation", &self.logging.rotation),
+      ("access_log.rotation", &self.access_log.rotation),
+    ] {
+      if rotation.max_size == Some(0) {
+        problems
+          .push(format!("{key}.max_size: 1以上にしてください"));
+      }
+    }
+    problems
+  }
+}
+
+/// コンフィグ読み込み時のエラー
+#[derive(Debug)]
+pub enum ConfigError {
+  /// コンフィグファイルが無かったので初期設定を書き出した
+  Created(PathBuf),
+
+  /// コンフィグファイルの読み書きができなかった
+  Io {
+    path: PathBuf,
+    error: std::io::Error,
+  },
+
+  /// コンフィグファイルの構文もしくは型が不正
+  Parse { location: String, error: String },
+
+  /// 存在しない設定項目を環境変数で上書きしようとした
+  UnknownEnv(String),
+
+  /// 検証で問題が見つかった
+  Invalid(Vec<String>),
+}
+impl std::fmt::Display for ConfigError {
+  fn fmt(
+    &self,
+    f: &mut std::fmt::Formatter<'_>,
+  ) -> std::fmt::Result {
+    match self {
+      Self::Created(path) => f.write_fmt(format_args!(
+        "コンフィグファイルが無かったので初期設定を{}に書き出しました。\
+          編集してから起動し直してください",
+        path.display()
+      )),
+      Self::Io { path, error } => f.write_fmt(format_args!(
+        "コンフィグファイル{}にアクセスできません: {error}",
+        path.display()
+      )),
+      Self::Parse { location, error } => f.write_fmt(
+        format_args!("コンフィグの{location}が不正です: {error}"),
+      ),
+      Self::UnknownEnv(name) => f.write_fmt(format_args!(
+        "環境変数{name}に当たる設定項目がありません"
+      )),
+      Self::Invalid(problems) => {
+        f.write_str("コンフィグに問題があります:")?;
+        for problem in problems {
+          f.write_fmt(format_args!("\n  - {problem}"))?;
+        }
+        Ok(())
+      }
+    }
+  }
+}
+impl std::error::Error for ConfigError {}
+
+static CONFIG: OnceLock<RwLock<Arc<Config>>> = OnceLock::new();
+
+/// ファイルの更新を確認する間隔
+const WATCH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// 現在のコンフィグを取得する
+/// 再読み込みされても取得済みのものは変わらないので、一連の処理では一度だけ取得すること
+pub fn get() -> Arc<Config> {
+  CONFIG
+    .get()
+    .expect("config is not initialized")
+    .read()
+    .clone()
+}
+
+/// コンフィグを読み込み、検証してから使えるようにする
+/// ファイルが無ければ初期設定を書き出してエラーとする
+pub fn init(path: &Path) -> Result<(), ConfigError> {
+  let config = match load(path) {
+    Err(ConfigError::Io { error, .. })
+      if error.kind() == std::io::ErrorKind::NotFound =>
+    {
+      std::fs::write(
+        path,
+        serde_json::to_vec_pretty(&Config::default())
+          .expect("default config must be serializable"),
+      )
+      .map_err(|error| ConfigError::Io {
+        path: path.into(),
+        error,
+      })?;
+      return Err(ConfigError::Created(path.into()));
+    }
+    r => r?,
+  };
+  let problems = config.validate();
+  if !problems.is_empty() {
+    return Err(ConfigError::Invalid(problems));
+  }
+  let _ = CONFIG.set(RwLock::new(Arc::new(config)));
+  Ok(())
+}
+
+/// コンフィグを読み込み直して差し替える
+/// 検証に失敗した場合は現在のコンフィグを維持する
+pub fn reload(path: &Path) -> Result<(), ConfigError> {
+  let mut config = load(path)?;
+  let problems = config.validate();
+  if !problems.is_empty() {
+    return Err(ConfigError::Invalid(problems));
+  }
+  let lock = CONFIG.get().expect("config is not initialized");
+  let mut current = lock.write();
+
+  // 構造的な設定は起動時のものを引き継ぐ
+  if config.origin_time != current.origin_time
+    || config.listen_port != current.listen_port
+    || config.listeners != current.listeners
+    || config.log_file != current.log_file
+    || config.access_log.file != current.access_log.file
+    || config.preferences.secret_file
+      != current.preferences.secret_file
+    || config.service.counter.file
+      != current.service.counter.file
+  {
+    log::warn!(
+      "origin_time, listen_port, listeners, log_file, \
+        access_log.file, preferences.secret_file and \
+        service.counter.file changes take effect only after restart"
+    );
+  }
+  config.origin_time = current.origin_time;
+  config.listen_port = current.listen_port;
+  config.listeners = current.listeners.clone();
+  config.log_file = current.log_file.clone();
+  config.access_log.file = current.access_log.file.clone();
+  config.preferences.secret_file =
+    current.preferences.secret_file.clone();
+  config.service.counter.file =
+    current.service.counter.file.clone();
+
+  logging::reconfigure(&config);
+  access_log::reconfigure(&config);
+  theme::reconfigure(&config);
+  *current = Arc::new(config);
+  main_page::bump_content_version();
+  Ok(())
+}
+
+/// SIGHUPの受信もしくはファイルの更新でコンフィグを再読み込みするタスクを起動する
+pub fn spawn_reloader(
+  path: PathBuf,
+) -> Result<(), Box<dyn std::error::Error>> {
+  use tokio::signal::unix::{SignalKind, signal};
+  let mut hangup = signal(SignalKind::hangup())?;
+  let modified = |path: &Path| {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+  };
+  let mut last_modified: Option<SystemTime> = modified(&path);
+  tokio::spawn(async move {
+    let mut interval = tokio::time::interval(WATCH_INTERVAL);
+    loop {
+      tokio::select! {
+        _ = hangup.recv() => {
+          log::info!("SIGHUP received, reloading config");
+        }
+        _ = interval.tick() => {
+          let current = modified(&path);
+          if current == last_modified {
+            continue;
+          }
+          log::info!("{} was modified, reloading config", path.display());
+        }
+      }
+      last_modified = modified(&path);
+      match reload(&path) {
+        Ok(()) => log::info!("config reloaded"),
+        Err(e) => {
+          log::error!(
+            "config reload failed, keeping current: {e}"
+          )
+        }
+      }
+    }
+  });
+  Ok(())
+}
+
+/// 初期値・コンフィグファイル・環境変数を重ねてコンフィグを読み込む(検証はしない)
+pub fn load(path: &Path) -> Result<Config, ConfigError> {
+  let mut value = serde_json::to_value(Config::default())
+    .expect("default config must be serializable");
+
+  let src =
+    std::fs::read(path).map_err(|error| ConfigError::Io {
+      path: path.into(),
+      error,
+    })?;
+  let file =
+    serde_json::from_slice::<Value>(&src).map_err(|e| {
+      ConfigError::Parse {
+        location: format!(
+          "{}:{}:{}",
+          path.display(),
+          e.line(),
+          e.column()
+        ),
+        error: e.to_string(),
+      }
+    })?;
+  merge(&mut value, file);
+  apply_env(&mut value, std::env::vars())?;
+
+  serde_path_to_error::deserialize(value).map_err(|e| {
+    ConfigError::Parse {
+      location: e.path().to_string(),
+      error: e.inner().to_string(),
+    }
+  })
+}
+
+/// `src`の内容を`dst`に重ねる(オブジェクトは再帰的にマージし、それ以外は置き換え)
+fn merge(dst: &mut Value, src: Value) {
+  match (dst, src) {
+    (Value::Object(dst), Value::Object(src)) => {
+      for (key, src) in src {
+        match dst.get_mut(&key) {
+          Some(dst) => merge(dst, src),
+          None => {
+            dst.insert(key, src);
+          }
+        }
+      }
+    }
+    (dst, src) => *dst = src,
+  }
+}
+
+/// `TMDX4_`で始まる環境変数で設定項目を上書きする
+fn apply_env(
+  value: &mut Value,
+  vars: impl Iterator<Item = (String, String)>,
+) -> Result<(), ConfigError> {
+  for (name, raw) in vars {
+    if name == ENV_CONFIG_PATH {
+      continue;
+    }
+    let Some(key) = name.strip_prefix(ENV_PREFIX) else {
+      continue;
+    };
+    let mut target = &mut *value;
+    for segment in key.split("__") {
+      target = match target.get_mut(segment.to_lowercase()) {
+        Some(target) => target,
+        None => return Err(ConfigError::UnknownEnv(name)),
+      };
+    }
+    // 文字列の項目はそのまま、それ以外はJSONとして解釈してみる
+    *target = match target {
+      Value::String(_) => Value::String(raw),
+      _ => {
+        serde_json::from_str(&raw).unwrap_or(Value::String(raw))
+      }
+    };
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn env(
+    vars: &[(&str, &str)],
+  ) -> impl Iterator<Item = (String, String)> {
+    vars
+      .iter()
+      .map(|(k, v)| (k.to_string(), v.to_string()))
+      .collect::<Vec<_>>()
+      .into_iter()
+  }
+
+  fn defaults() -> Value {
+    serde_json::to_value(Config::default()).unwrap()
+  }
+
+  #[test]
+  fn file_values_are_merged_over_defaults() {
+    let mut value = defaults();
+    merge(
+      &mut value,
+      serde_json::json!({
+        "listen_port": 8443,
+        "maintenance_page": { "pswd_len_min": 20 },
+      }),
+    );
+    let config =
+      serde_json::from_value::<Config>(value).unwrap();
+    assert_eq!(config.listen_port, 8443);
+    assert_eq!(config.maintenance_page.pswd_len_min, 20);
+    // 指定の無い項目は初期値のまま
+    assert_eq!(
+      config.shutdown_timeout_secs,
+      Config::default().shutdown_timeout_secs
+    );
+  }
+
+  #[test]
+  fn env_overrides_nested_keys() {
+    let mut value = defaults();
+    apply_env(
+      &mut value,
+      env(&[
+        ("TMDX4_MAINTENANCE_PAGE__PSWD_LEN_MIN", "20"),
+        ("TMDX4_LOG_FILE", "123"),
+        ("TMDX4_CONFIG", "other.json"),
+        ("PATH", "/usr/bin"),
+      ]),
+    )
+    .unwrap();
+    let config =
+      serde_json::from_value::<Config>(value).unwrap();
+    assert_eq!(config.maintenance_page.pswd_len_min, 20);
+    // 文字列の項目はJSONとして解釈しない
+    assert_eq!(config.log_file, "123");
+  }
+
+  #[test]
+  fn unknown_env_is_rejected() {
+    for name in [
+      "TMDX4_NO_SUCH_KEY",
+      "TMDX4_MAINTENANCE_PAGE__NO_SUCH_KEY",
+      "TMDX4_LISTEN_PORT__INNER",
+    ] {
+      let mut value = defaults();
+      match apply_env(&mut value, env(&[(name, "1")])) {
+        Err(ConfigError::UnknownEnv(n)) => assert_eq!(n, name),
+        r => panic!("{name}: {r:?}"),
+      }
+    }
+  }
+
+  #[test]
+  fn validate_reports_every_problem() {
+    let mut config = Config {
+      listen_port: 0,
+      ..Config::default()
+    };
+    config.maintenance_page.pswd_len_min = 0;
+    let problems = config.validate();
+    for key in ["listen_port:", "maintenance_page.pswd_len_min:"]
+    {
+      assert!(
+        problems.iter().any(|p| p.starts_with(key)),
+        "{problems:?}"
+      );
+    }
+  }
+}
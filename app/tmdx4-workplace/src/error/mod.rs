@@ -0,0 +1,206 @@
+//! ハンドラで起きるエラーの実装
+//!
+//! ハンドラは[`AppError`]を返し、エラーごとにHTTPのステータス・BSODの文言・ログの行を決める。
+//! ログとエラーページには同じリクエストIDが載るので、利用者からの報告とログを突き合わせられる
+
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+use axum::{
+  http::StatusCode,
+  response::{IntoResponse, Response},
+};
+
+use crate::{bsod::Bsod, usersys::UserDataError};
+
+/// ハンドラで起きるエラー
+#[derive(Debug)]
+pub enum AppError {
+  /// ユーザデータの操作のエラー
+  UserData(UserDataError),
+
+  /// ファイル等の入出力のエラー
+  Io(std::io::Error),
+
+  /// ページの生成のエラー
+  Render(askama::Error),
+
+  /// MsgPackのデコードのエラー
+  MPackDecode(rmp_serde::decode::Error),
+
+  /// MsgPackのエンコードのエラー
+  MPackEncode(rmp_serde::encode::Error),
+
+  /// ユーザ名もしくはパスワードが一致しない
+  AuthenticationFailed,
+}
+impl AppError {
+  /// 応答のステータス
+  pub fn status_code(&self) -> StatusCode {
+    match self {
+      Self::UserData(e) => match e {
+        UserDataError::UserIDConflict => StatusCode::CONFLICT,
+        UserDataError::InvalidCharInIdent => {
+          StatusCode::BAD_REQUEST
+        }
+        UserDataError::UserDataLoadError(e)
+        | UserDataError::UserDataSaveError(e) => io_status(e),
+        UserDataError::UserDataLoadCannot { .. }
+        | UserDataError::UserDataInitializeError(_)
+        | UserDataError::PasswordHashError(_)
+        | UserDataError::Argon2Error(_)
+        | UserDataError::MPackDecodeError(_)
+        | UserDataError::MPackEncodeError(_)
+        | UserDataError::LogicError(_) => {
+          StatusCode::INTERNAL_SERVER_ERROR
+        }
+      },
+      Self::Io(e) => io_status(e),
+      Self::Render(_)
+      | Self::MPackDecode(_)
+      | Self::MPackEncode(_) => {
+        StatusCode::INTERNAL_SERVER_ERROR
+      }
+      Self::AuthenticationFailed => StatusCode::FORBIDDEN,
+    }
+  }
+
+  /// BSODの本文(Noneならステータスごとの既定の文言)
+  /// サーバ内部のエラーは詳細を出さない
+  fn message(&self) -> Option<&'static str> {
+    match self {
+      Self::UserData(UserDataError::UserIDConflict) => {
+        Some("ユーザIDが他のユーザと競合しています。")
+      }
+      Self::UserData(UserDataError::InvalidCharInIdent) => Some(
+        "ユーザ名に使用できない文字(/ \\ * + ? . , ~ ^ < > \" ')が含まれています。",
+      ),
+      Self::AuthenticationFailed => Some(
+        "ユーザ名もしくはパスワードが一致しない為、ログインできませんでした。",
+      ),
+      _ => None,
+    }
+  }
+}
+impl Display for AppError {
+  fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+    match self {
+      Self::UserData(e) => write!(f, "user data error: {e}"),
+      Self::Io(e) => write!(f, "IO error: {e}"),
+      Self::Render(e) => write!(f, "page rendering error: {e}"),
+      Self::MPackDecode(e) => {
+        write!(f, "Message pack decode error: {e}")
+      }
+      Self::MPackEncode(e) => {
+        write!(f, "Message pack encode error: {e}")
+      }
+      Self::AuthenticationFailed => {
+        f.write_str("authentication failed")
+      }
+    }
+  }
+}
+impl std::error::Error for AppError {
+  fn source(
+    &self,
+  ) -> Option<&(dyn std::error::Error + 'static)> {
+    match self {
+      Self::UserData(e) => Some(e),
+      Self::Io(e) => Some(e),
+      Self::Render(e) => Some(e),
+      Self::MPackDecode(e) => Some(e),
+      Self::MPackEncode(e) => Some(e),
+      Self::AuthenticationFailed => None,
+    }
+  }
+}
+impl From<UserDataError> for AppError {
+  fn from(e: UserDataError) -> Self {
+    Self::UserData(e)
+  }
+}
+impl From<std::io::Error> for AppError {
+  fn from(e: std::io::Error) -> Self {
+    Self::Io(e)
+  }
+}
+impl From<askama::Error> for AppError {
+  fn from(e: askama::Error) -> Self {
+    Self::Render(e)
+  }
+}
+impl From<rmp_serde::decode::Error> for AppError {
+  fn from(e: rmp_serde::decode::Error) -> Self {
+    Self::MPackDecode(e)
+  }
+}
+impl From<rmp_serde::encode::Error> for AppError {
+  fn from(e: rmp_serde::encode::Error) -> Self {
+    Self::MPackEncode(e)
+  }
+}
+impl IntoResponse for AppError {
+  fn into_response(self) -> Response {
+    let status_code = self.status_code();
+    if status_code.is_server_error() {
+      log::error!("{status_code}: {self}");
+    } else {
+      log::warn!("{status_code}: {self}");
+    }
+    let bsod = Bsod::new(status_code);
+    match self.message() {
+      Some(message) => bsod.text(message),
+      None => bsod,
+    }
+    .into_response()
+  }
+}
+
+/// 入出力のエラーのステータス
+fn io_status(e: &std::io::Error) -> StatusCode {
+  match e.kind() {
+    std::io::ErrorKind::StorageFull
+    | std::io::ErrorKind::QuotaExceeded => {
+      StatusCode::INSUFFICIENT_STORAGE
+    }
+    std::io::ErrorKind::TimedOut => {
+      StatusCode::SERVICE_UNAVAILABLE
+    }
+    _ => StatusCode::INTERNAL_SERVER_ERROR,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn user_errors_are_client_errors() {
+    for e in [
+      UserDataError::UserIDConflict,
+      UserDataError::InvalidCharInIdent,
+    ] {
+      let e = AppError::from(e);
+      assert!(e.status_code().is_client_error());
+      assert!(e.message().is_some());
+    }
+  }
+
+  #[test]
+  fn internal_errors_do_not_leak_details() {
+    let e = AppError::from(UserDataError::UserDataSaveError(
+      std::io::Error::other("/secret/path"),
+    ));
+    assert_eq!(
+      e.status_code(),
+      StatusCode::INTERNAL_SERVER_ERROR
+    );
+    assert!(e.message().is_none());
+    let e = AppError::from(std::io::Error::from(
+      std::io::ErrorKind::StorageFull,
+    ));
+    assert_eq!(
+      e.status_code(),
+      StatusCode::INSUFFICIENT_STORAGE
+    );
+  }
+}
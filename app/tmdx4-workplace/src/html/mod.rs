@@ -0,0 +1,191 @@
+//! ページに埋め込む値の包み
+//!
+//! 利用者の入力に由来する値は[`Escaped`]で包み、特殊文字を文字参照にしてから埋め込む。
+//! エスケープせずに埋め込めるのは[`Trusted`]で包んだものだけとし、
+//! `Trusted`はこのモジュールに並べた生成元(ソース中の定数・検証済みのテーマのCSS)からしか作れないようにする。
+//! テンプレートで`|safe`を使っていないことはテストで確かめる
+
+use std::fmt::{Display, Formatter, Result as FmtResult, Write};
+
+/// 特殊文字を文字参照にして書き込む
+struct EscapeWriter<'a, 'b>(&'a mut Formatter<'b>);
+impl Write for EscapeWriter<'_, '_> {
+  fn write_str(&mut self, s: &str) -> FmtResult {
+    let mut rest = s;
+    while let Some(i) = rest.find(['&', '<', '>', '"', '\'']) {
+      self.0.write_str(&rest[..i])?;
+      self.0.write_str(match rest.as_bytes()[i] {
+        b'&' => "&amp;",
+        b'<' => "&lt;",
+        b'>' => "&gt;",
+        b'"' => "&quot;",
+        _ => "&#39;",
+      })?;
+      rest = &rest[i + 1..];
+    }
+    self.0.write_str(rest)
+  }
+}
+
+/// 表示するときにHTMLの特殊文字をエスケープする値
+/// 属性値(引用符の種類を問わない)と要素の内容のどちらに埋め込んでもよい
+#[derive(Clone, Copy, Debug)]
+pub struct Escaped<T>(pub T);
+impl<T: Display> Display for Escaped<T> {
+  fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+    write!(EscapeWriter(f), "{}", self.0)
+  }
+}
+impl<T: Display> askama::filters::HtmlSafe for Escaped<T> {}
+
+/// エスケープせずに埋め込む値
+/// 利用者の入力を含まないことが分かっているものにだけ使うので、生成元を以下に限る
+#[derive(Clone, Copy, Debug)]
+pub struct Trusted<T>(T);
+impl Trusted<&'static str> {
+  /// ソースに書かれた定数
+  pub(crate) const fn constant(html: &'static str) -> Self {
+    Self(html)
+  }
+}
+impl<'a> Trusted<&'a str> {
+  /// 全てのテーマのCSS(値は読み込み時に検証済み)
+  pub(crate) fn theme_css(
+    themes: &'a crate::theme::Themes,
+  ) -> Self {
+    Self(themes.validated_css())
+  }
+}
+impl<T: Display> Display for Trusted<T> {
+  fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+    self.0.fmt(f)
+  }
+}
+impl<T: Display> askama::filters::HtmlSafe for Trusted<T> {}
+
+#[cfg(test)]
+mod tests {
+  use std::path::Path;
+
+  use super::*;
+
+  const HOSTILE: &[&str] = &[
+    "'><script>alert(1)</script>",
+    "\" onmouseover=\"alert(1)",
+    "</textarea><img src=x onerror=alert(1)>",
+    "&lt;already&gt; & <b>",
+    "' autofocus onfocus='alert(1)",
+  ];
+
+  #[test]
+  fn escaped_leaves_no_markup() {
+    for input in HOSTILE {
+      let output = Escaped(input).to_string();
+      for c in ['<', '>', '"', '\''] {
+        assert!(!output.contains(c), "{input:?} -> {output:?}");
+      }
+    }
+  }
+
+  #[test]
+  fn escaped_is_reversible() {
+    let output = Escaped("a&b<c>d\"e'f").to_string();
+    assert_eq!(output, "a&amp;b&lt;c&gt;d&quot;e&#39;f");
+    assert_eq!(Escaped("ツナマヨ").to_string(), "ツナマヨ");
+  }
+
+  #[test]
+  fn escaped_is_not_escaped_twice_by_templates() {
+    #[derive(askama::Template)]
+    #[template(
+      source = "<p title='{{ value }}'>{{ value }}</p>",
+      ext = "html"
+    )]
+    struct Page<'a> {
+      value: Escaped<&'a str>,
+    }
+    let page = Page {
+      value: Escaped("<&>"),
+    };
+    assert_eq!(
+      askama::Template::render(&page).unwrap(),
+      "<p title='&lt;&amp;&gt;'>&lt;&amp;&gt;</p>"
+    );
+  }
+
+  #[test]
+  fn trusted_is_written_as_is() {
+    assert_eq!(Trusted::constant("<br>").to_string(), "<br>");
+  }
+
+  /// エスケープを外す書き方。空白を除き引用符を`"`に揃えた後で探す
+  const BYPASSES: &[&str] = &[
+    "|safe",
+    "%filtersafe",
+    "%-filtersafe",
+    "%+filtersafe",
+    "%~filtersafe",
+    "|escape(\"none\")",
+    "|e(\"none\")",
+    "filterescape(\"none\")",
+    "filtere(\"none\")",
+    "escaper=\"none\"",
+  ];
+
+  /// エスケープしない埋め込みは`Trusted`に限るので、テンプレートでエスケープを外さない
+  fn assert_no_safe_filter(dir: &Path) {
+    for entry in std::fs::read_dir(dir).unwrap() {
+      let path = entry.unwrap().path();
+      if path.is_dir() {
+        assert_no_safe_filter(&path);
+      } else {
+        let source = std::fs::read_to_string(&path).unwrap();
+        let source = normalize(&source);
+        for bypass in BYPASSES {
+          assert!(
+            !source.contains(bypass),
+            "{} uses {bypass}",
+            path.display()
+          );
+        }
+      }
+    }
+  }
+
+  fn normalize(source: &str) -> String {
+    source
+      .chars()
+      .filter(|c| !c.is_whitespace())
+      .map(|c| if c == '\'' { '"' } else { c })
+      .collect()
+  }
+
+  #[test]
+  fn escaping_bypasses_are_detected() {
+    for source in [
+      "{{ a|safe }}",
+      "{% filter safe %}x{% endfilter %}",
+      "{%- filter safe -%}x{% endfilter %}",
+      "{{ a | escape('none') }}",
+      "{{ a|e(\"none\") }}",
+      "{% filter escape(\"none\") %}x{% endfilter %}",
+    ] {
+      let source = normalize(source);
+      assert!(
+        BYPASSES.iter().any(|bypass| source.contains(bypass)),
+        "{source}"
+      );
+    }
+    let source = normalize("{{ a|e }} {{ a|escape(\"html\") }}");
+    assert!(
+      !BYPASSES.iter().any(|bypass| source.contains(bypass))
+    );
+  }
+
+  #[test]
+  fn templates_do_not_use_safe_filter() {
+    assert_no_safe_filter(
+      &Path::new(env!("CARGO_MANIFEST_DIR")).join("templates"),
+    );
+  }
+}
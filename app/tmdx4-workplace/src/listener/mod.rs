@@ -0,0 +1,363 @@
+//! 待ち受けソケットの実装
+//!
+//! TCP(IPv4/IPv6)・TLS・Unixドメインソケット・systemdのソケット活性化で
+//! 引き継いだファイル記述子のいずれでも待ち受けられるようにする
+
+pub mod tls;
+
+use std::{
+  net::{Ipv4Addr, SocketAddr},
+  os::unix::fs::{DirBuilderExt, PermissionsExt},
+  path::{Path, PathBuf},
+};
+
+use axum::{
+  extract::connect_info::Connected, serve::IncomingStream,
+};
+use serde::{Deserialize, Serialize};
+use tokio::net::{TcpListener, UnixListener};
+
+use tls::TlsListener;
+
+/// 待ち受けについてのコンフィグ
+#[derive(
+  Deserialize, Serialize, Clone, PartialEq, Eq, Debug,
+)]
+#[serde(tag = "type")]
+pub enum ListenerConfig {
+  /// 指定したアドレスとポートで待ち受ける(`[::1]:8080`等)
+  #[serde(alias = "tcp")]
+  Tcp { addr: SocketAddr },
+
+  /// TLS(HTTPS)で待ち受ける
+  /// 証明書(チェーン)と秘密鍵はPEM形式で、更新されれば自動で読み込み直す
+  #[serde(alias = "tls")]
+  Tls {
+    addr: SocketAddr,
+    cert: PathBuf,
+    key: PathBuf,
+  },
+
+  /// 全てのリクエストをHTTPSの同じパスへ転送する
+  #[serde(alias = "redirect")]
+  Redirect {
+    addr: SocketAddr,
+
+    /// 転送先のポート(省略時は443)
+    #[serde(default)]
+    https_port: Option<u16>,
+  },
+
+  /// Unixドメインソケットで待ち受ける
+  #[serde(alias = "unix")]
+  Unix {
+    path: PathBuf,
+
+    /// パーミッション(8進数の文字列、`"0660"`等)
+    #[serde(default)]
+    mode: Option<String>,
+
+    /// 所有者のUID
+    #[serde(default)]
+    owner: Option<u32>,
+
+    /// 所有グループのGID
+    #[serde(default)]
+    group: Option<u32>,
+  },
+
+  /// systemdのソケット活性化で引き継いだファイル記述子を全て使う
+  #[serde(alias = "systemd")]
+  Systemd,
+}
+impl ListenerConfig {
+  /// コンフィグの内容を検証し、見つかった問題を`problems`に追加する
+  pub fn validate(&self, key: &str, problems: &mut Vec<String>) {
+    match self {
+      Self::Tcp { addr } | Self::Redirect { addr, .. }
+        if addr.port() == 0 =>
+      {
+        problems.push(format!(
+          "{key}.addr: 0は待ち受けポートに使えません"
+        ));
+      }
+      Self::Tls {
+        addr,
+        cert,
+        key: key_file,
+      } => {
+        if addr.port() == 0 {
+          problems.push(format!(
+            "{key}.addr: 0は待ち受けポートに使えません"
+          ));
+        }
+        for (name, file) in [("cert", cert), ("key", key_file)] {
+          if !file.is_file() {
+            problems.push(format!(
+              "{key}.{name}: {}がありません",
+              file.display()
+            ));
+          }
+        }
+      }
+      Self::Unix { path, mode, .. } => {
+        if let Some(mode) = mode
+          && parse_mode(mode).is_none()
+        {
+          problems.push(format!(
+            "{key}.mode: {mode}は8進数のパーミッションではありません"
+          ));
+        }
+        if let Some(parent) =
+          path.parent().filter(|p| !p.as_os_str().is_empty())
+          && !parent.is_dir()
+        {
+          problems.push(format!(
+            "{key}.path: ディレクトリ{}がありません",
+            parent.display()
+          ));
+        }
+      }
+      _ => {}
+    }
+  }
+}
+
+fn parse_mode(mode: &str) -> Option<u32> {
+  u32::from_str_radix(mode.trim(), 8)
+    .ok()
+    .filter(|mode| *mode <= 0o7777)
+}
+
+/// Unixドメインソケットで待ち受ける
+/// 本人しか入れないディレクトリの中で作って権限と所有者を設定してから`path`に移すので、
+/// 設定より緩い権限のソケットが`path`に現れることはない
+fn bind_unix(
+  path: &Path,
+  mode: Option<u32>,
+  owner: Option<u32>,
+  group: Option<u32>,
+) -> std::io::Result<UnixListener> {
+  let file_name = path.file_name().ok_or_else(|| {
+    std::io::Error::other(format!(
+      "{}: not a socket file path",
+      path.display()
+    ))
+  })?;
+  let private = path.with_file_name(format!(
+    ".{}.{}",
+    file_name.to_string_lossy(),
+    std::process::id()
+  ));
+  std::fs::DirBuilder::new().mode(0o700).create(&private)?;
+  let bind = || {
+    let temp = private.join(file_name);
+    let listener = UnixListener::bind(&temp)?;
+    if let Some(mode) = mode {
+      std::fs::set_permissions(
+        &temp,
+        std::fs::Permissions::from_mode(mode),
+      )?;
+    }
+    if owner.is_some() || group.is_some() {
+      std::os::unix::fs::chown(&temp, owner, group)?;
+    }
+    std::fs::rename(&temp, path)?;
+    Ok(listener)
+  };
+  let r = bind();
+  let _ = std::fs::remove_dir_all(&private);
+  r
+}
+
+/// 接続元のアドレス
+#[derive(Clone, Copy, Debug)]
+pub enum PeerAddr {
+  Tcp(SocketAddr),
+
+  Tls(SocketAddr),
+
+  /// Unixドメインソケット経由(同一ホストのリバースプロキシとみなす)
+  Unix,
+}
+impl Connected<IncomingStream<'_, TcpListener>> for PeerAddr {
+  fn connect_info(
+    stream: IncomingStream<'_, TcpListener>,
+  ) -> Self {
+    Self::Tcp(*stream.remote_addr())
+  }
+}
+impl Connected<IncomingStream<'_, TlsListener>> for PeerAddr {
+  fn connect_info(
+    stream: IncomingStream<'_, TlsListener>,
+  ) -> Self {
+    Self::Tls(*stream.remote_addr())
+  }
+}
+impl Connected<IncomingStream<'_, UnixListener>> for PeerAddr {
+  fn connect_info(
+    _stream: IncomingStream<'_, UnixListener>,
+  ) -> Self {
+    Self::Unix
+  }
+}
+
+/// 待ち受けを開始したソケット
+pub enum BoundListener {
+  Tcp(TcpListener),
+
+  Tls(TlsListener),
+
+  /// HTTPSへ転送するだけの待ち受け(転送先のポートと組にする)
+  Redirect(TcpListener, u16),
+
+  /// 終了時に削除するソケットファイルのパスと組にする
+  /// (systemdから引き継いだものは削除しない)
+  Unix(UnixListener, Option<PathBuf>),
+}
+impl std::fmt::Display for BoundListener {
+  fn fmt(
+    &self,
+    f: &mut std::fmt::Formatter<'_>,
+  ) -> std::fmt::Result {
+    match self {
+      Self::Tcp(listener) => match listener.local_addr() {
+        Ok(addr) => f.write_fmt(format_args!("tcp {addr}")),
+        Err(_) => f.write_str("tcp (unknown)"),
+      },
+      Self::Tls(listener) => {
+        match axum::serve::Listener::local_addr(listener) {
+          Ok(addr) => f.write_fmt(format_args!("tls {addr}")),
+          Err(_) => f.write_str("tls (unknown)"),
+        }
+      }
+      Self::Redirect(listener, https_port) => {
+        match listener.local_addr() {
+          Ok(addr) => f.write_fmt(format_args!(
+            "redirect {addr} -> https port {https_port}"
+          )),
+          Err(_) => f.write_str("redirect (unknown)"),
+        }
+      }
+      Self::Unix(listener, _) => {
+        match listener.local_addr().ok().and_then(|addr| {
+          addr.as_pathname().map(|p| p.display().to_string())
+        }) {
+          Some(path) => f.write_fmt(format_args!("unix {path}")),
+          None => f.write_str("unix (unnamed)"),
+        }
+      }
+    }
+  }
+}
+
+/// コンフィグに従って全ての待ち受けを開始する
+/// `listeners`が空なら従来通り`0.0.0.0:listen_port`で待ち受ける
+pub async fn bind_all(
+  config: &crate::config::Config,
+) -> Result<Vec<BoundListener>, Box<dyn std::error::Error>> {
+  if config.listeners.is_empty() {
+    let listener = TcpListener::bind((
+      Ipv4Addr::UNSPECIFIED,
+      config.listen_port,
+    ))
+    .await?;
+    return Ok(vec![BoundListener::Tcp(listener)]);
+  }
+  let mut bound = Vec::new();
+  for listener in &config.listeners {
+    match listener {
+      ListenerConfig::Tcp { addr } => {
+        bound.push(BoundListener::Tcp(
+          TcpListener::bind(addr).await?,
+        ));
+      }
+      ListenerConfig::Tls { addr, cert, key } => {
+        bound.push(BoundListener::Tls(
+          TlsListener::bind(*addr, cert, key).await?,
+        ));
+      }
+      ListenerConfig::Redirect { addr, https_port } => {
+        bound.push(BoundListener::Redirect(
+          TcpListener::bind(addr).await?,
+          https_port.unwrap_or(443),
+        ));
+      }
+      ListenerConfig::Unix {
+        path,
+        mode,
+        owner,
+        group,
+      } => {
+        // 前回の起動で残ったソケットファイルを片付ける
+        if std::fs::symlink_metadata(path).is_ok_and(|m| {
+          std::os::unix::fs::FileTypeExt::is_socket(
+            &m.file_type(),
+          )
+        }) {
+          std::fs::remove_file(path)?;
+        }
+        let listener = bind_unix(
+          path,
+          mode.as_deref().and_then(parse_mode),
+          *owner,
+          *group,
+        )?;
+        bound.push(BoundListener::Unix(
+          listener,
+          Some(path.clone()),
+        ));
+      }
+      ListenerConfig::Systemd => {
+        let mut fds = listenfd::ListenFd::from_env();
+        if fds.len() == 0 {
+          return Err(Box::from(
+            "systemd listener is configured but no file descriptor \
+              was passed (LISTEN_FDS)",
+          ));
+        }
+        for i in 0..fds.len() {
+          if let Ok(Some(listener)) = fds.take_tcp_listener(i) {
+            listener.set_nonblocking(true)?;
+            bound.push(BoundListener::Tcp(
+              TcpListener::from_std(listener)?,
+            ));
+          } else if let Some(listener) =
+            fds.take_unix_listener(i)?
+          {
+            listener.set_nonblocking(true)?;
+            bound.push(BoundListener::Unix(
+              UnixListener::from_std(listener)?,
+              None,
+            ));
+          }
+        }
+      }
+    }
+  }
+  Ok(bound)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn unix_sockets_appear_with_the_configured_mode() {
+    let dir = std::env::temp_dir()
+      .join(format!("tmdx4-listener-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("app.sock");
+    let listener =
+      bind_unix(&path, Some(0o600), None, None).unwrap();
+    let metadata = std::fs::metadata(&path).unwrap();
+    assert_eq!(metadata.permissions().mode() & 0o7777, 0o600);
+    // 作業用のディレクトリは残さない
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+    // 移した後のパスで接続できる
+    let _client =
+      tokio::net::UnixStream::connect(&path).await.unwrap();
+    listener.accept().await.unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+}
@@ -0,0 +1,364 @@
+//! TLS(HTTPS)の待ち受けの実装
+//!
+//! 証明書と秘密鍵のファイルは定期的に更新を確認し、変わっていれば
+//! 新しい接続から差し替えたものを使う
+
+use std::{
+  net::SocketAddr,
+  path::{Path, PathBuf},
+  sync::Arc,
+  time::{Duration, SystemTime},
+};
+
+use axum::{
+  Router,
+  extract::{ConnectInfo, Request},
+  http::{HeaderValue, StatusCode, header},
+  middleware::Next,
+  response::{IntoResponse, Redirect, Response},
+};
+use parking_lot::RwLock;
+use rustls::{
+  ServerConfig,
+  crypto::CryptoProvider,
+  pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+  server::{ClientHello, ResolvesServerCert},
+  sign::CertifiedKey,
+};
+use serde::{Deserialize, Serialize};
+use tokio::{
+  net::{TcpListener, TcpStream},
+  task::JoinSet,
+};
+use tokio_rustls::{TlsAcceptor, server::TlsStream};
+
+use super::PeerAddr;
+
+/// ハンドシェイクがこれ以上かかる接続は切る
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// 証明書ファイルの更新を確認する間隔
+const WATCH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// HSTS(`Strict-Transport-Security`)についてのコンフィグ
+#[derive(Deserialize, Serialize, Clone)]
+pub struct HstsConfig {
+  /// TLSの待ち受けで受けたレスポンスにヘッダを付けるか
+  pub enabled: bool,
+
+  /// `max-age`(秒)
+  pub max_age: u64,
+
+  pub include_subdomains: bool,
+
+  pub preload: bool,
+}
+impl Default for HstsConfig {
+  fn default() -> Self {
+    Self {
+      enabled: false,
+      max_age: 60 * 60 * 24 * 365,
+      include_subdomains: false,
+      preload: false,
+    }
+  }
+}
+impl HstsConfig {
+  /// コンフィグの内容を検証し、見つかった問題を`problems`に追加する
+  pub fn validate(&self, problems: &mut Vec<String>) {
+    // preloadリストへの登録条件に合わせる
+    if self.preload
+      && (!self.include_subdomains
+        || self.max_age < 60 * 60 * 24 * 365)
+    {
+      problems.push(
+        "hsts.preload: include_subdomainsを有効にし、\
+          max_ageを31536000以上にしてください"
+          .into(),
+      );
+    }
+  }
+
+  fn header_value(&self) -> HeaderValue {
+    let mut value = format!("max-age={}", self.max_age);
+    if self.include_subdomains {
+      value.push_str("; includeSubDomains");
+    }
+    if self.preload {
+      value.push_str("; preload");
+    }
+    HeaderValue::from_str(&value)
+      .expect("HSTS header value is always visible ASCII")
+  }
+}
+
+/// TLSで受けたリクエストのレスポンスにHSTSのヘッダを付ける
+pub async fn hsts(req: Request, next: Next) -> Response {
+  let over_tls = matches!(
+    req.extensions().get::<ConnectInfo<PeerAddr>>(),
+    Some(ConnectInfo(PeerAddr::Tls(_)))
+  );
+  let mut response = next.run(req).await;
+  let config = crate::config::get();
+  if over_tls && config.hsts.enabled {
+    response.headers_mut().insert(
+      header::STRICT_TRANSPORT_SECURITY,
+      config.hsts.header_value(),
+    );
+  }
+  response
+}
+
+/// 証明書を読み込み、鍵と対になっているか確かめる
+fn load_certified_key(
+  provider: &CryptoProvider,
+  cert: &Path,
+  key: &Path,
+) -> Result<CertifiedKey, Box<dyn std::error::Error>> {
+  let chain = CertificateDer::pem_file_iter(cert)
+    .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+    .map_err(|e| format!("{}: {e}", cert.display()))?;
+  if chain.is_empty() {
+    return Err(
+      format!("{}: no certificate found", cert.display()).into(),
+    );
+  }
+  let key = PrivateKeyDer::from_pem_file(key)
+    .map_err(|e| format!("{}: {e}", key.display()))?;
+  Ok(CertifiedKey::from_der(chain, key, provider)?)
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+  std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// 差し替え可能な証明書
+#[derive(Debug)]
+struct CertResolver(RwLock<Arc<CertifiedKey>>);
+impl ResolvesServerCert for CertResolver {
+  fn resolve(
+    &self,
+    _client_hello: ClientHello<'_>,
+  ) -> Option<Arc<CertifiedKey>> {
+    Some(self.0.read().clone())
+  }
+}
+
+/// 証明書ファイルの更新を監視し、変わっていれば読み込み直す
+/// 読み込みに失敗した場合はそれまでの証明書を使い続ける
+fn spawn_watcher(
+  resolver: Arc<CertResolver>,
+  provider: Arc<CryptoProvider>,
+  cert: PathBuf,
+  key: PathBuf,
+) {
+  tokio::spawn(async move {
+    let mut last = (modified(&cert), modified(&key));
+    let mut interval = tokio::time::interval(WATCH_INTERVAL);
+    interval.tick().await;
+    loop {
+      interval.tick().await;
+      let current = (modified(&cert), modified(&key));
+      if current == last {
+        continue;
+      }
+      last = current;
+      match load_certified_key(&provider, &cert, &key) {
+        Ok(certified) => {
+          *resolver.0.write() = Arc::new(certified);
+          log::info!("certificate reloaded: {}", cert.display());
+        }
+        Err(e) => log::error!(
+          "certificate reload failed, keeping the current one: {e}"
+        ),
+      }
+    }
+  });
+}
+
+/// TLSで待ち受けるリスナー
+/// ハンドシェイクは接続ごとに並行して行い、終わったものから返す
+pub struct TlsListener {
+  listener: TcpListener,
+  acceptor: TlsAcceptor,
+  handshakes:
+    JoinSet<Option<(TlsStream<TcpStream>, SocketAddr)>>,
+}
+impl TlsListener {
+  /// 証明書を読み込んで待ち受けを開始する
+  pub async fn bind(
+    addr: SocketAddr,
+    cert: &Path,
+    key: &Path,
+  ) -> Result<Self, Box<dyn std::error::Error>> {
+    let provider =
+      Arc::new(rustls::crypto::ring::default_provider());
+    let resolver = Arc::new(CertResolver(RwLock::new(
+      Arc::new(load_certified_key(&provider, cert, key)?),
+    )));
+    let mut config =
+      ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_cert_resolver(resolver.clone());
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    spawn_watcher(
+      resolver,
+      provider,
+      cert.to_path_buf(),
+      key.to_path_buf(),
+    );
+    Ok(Self {
+      listener: TcpListener::bind(addr).await?,
+      acceptor: TlsAcceptor::from(Arc::new(config)),
+      handshakes: JoinSet::new(),
+    })
+  }
+}
+impl axum::serve::Listener for TlsListener {
+  type Io = TlsStream<TcpStream>;
+  type Addr = SocketAddr;
+
+  async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+    loop {
+      tokio::select! {
+        accepted = self.listener.accept() => match accepted {
+          Ok((stream, addr)) => {
+            let acceptor = self.acceptor.clone();
+            self.handshakes.spawn(async move {
+              match tokio::time::timeout(
+                HANDSHAKE_TIMEOUT,
+                acceptor.accept(stream),
+              )
+              .await
+              {
+                Ok(Ok(stream)) => Some((stream, addr)),
+                Ok(Err(e)) => {
+                  log::debug!("TLS handshake with {addr} failed: {e}");
+                  None
+                }
+                Err(_) => {
+                  log::debug!("TLS handshake with {addr} timed out");
+                  None
+                }
+              }
+            });
+          }
+          // ファイル記述子の枯渇等。少し待ってから受け付けを再開する
+          Err(e) => {
+            log::error!("accept failed: {e}");
+            tokio::time::sleep(Duration::from_secs(1)).await;
+          }
+        },
+        Some(Ok(Some(established))) = self.handshakes.join_next() => {
+          return established;
+        }
+      }
+    }
+  }
+
+  fn local_addr(&self) -> std::io::Result<Self::Addr> {
+    self.listener.local_addr()
+  }
+}
+
+/// 全てのリクエストを同じパスのHTTPSへ転送するルーター
+/// `https_port`が443以外ならURLにポート番号を付ける
+pub fn redirect_router(https_port: u16) -> Router {
+  Router::new().fallback(async move |req: Request| {
+    let Some(host) = req
+      .headers()
+      .get(header::HOST)
+      .and_then(|host| host.to_str().ok())
+      .and_then(|host| {
+        host.parse::<axum::http::uri::Authority>().ok()
+      })
+    else {
+      return StatusCode::BAD_REQUEST.into_response();
+    };
+    let host = host.host();
+    let path = req
+      .uri()
+      .path_and_query()
+      .map(|p| p.as_str())
+      .unwrap_or("/");
+    let location = if https_port == 443 {
+      format!("https://{host}{path}")
+    } else {
+      format!("https://{host}:{https_port}{path}")
+    };
+    Redirect::permanent(&location).into_response()
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const YEAR: u64 = 60 * 60 * 24 * 365;
+
+  fn problems(config: &HstsConfig) -> Vec<String> {
+    let mut problems = Vec::new();
+    config.validate(&mut problems);
+    problems
+  }
+
+  #[test]
+  fn preload_requires_the_list_conditions() {
+    let ok = HstsConfig {
+      enabled: true,
+      max_age: YEAR,
+      include_subdomains: true,
+      preload: true,
+    };
+    assert!(problems(&ok).is_empty());
+    assert_eq!(
+      problems(&HstsConfig {
+        include_subdomains: false,
+        ..ok
+      })
+      .len(),
+      1
+    );
+    assert_eq!(
+      problems(&HstsConfig {
+        max_age: YEAR - 1,
+        ..ok
+      })
+      .len(),
+      1
+    );
+    // preloadしないなら短くても良い
+    assert!(
+      problems(&HstsConfig {
+        max_age: 300,
+        include_subdomains: false,
+        preload: false,
+        ..ok
+      })
+      .is_empty()
+    );
+  }
+
+  #[test]
+  fn header_value_lists_the_directives() {
+    let config = HstsConfig {
+      enabled: true,
+      max_age: YEAR,
+      include_subdomains: true,
+      preload: true,
+    };
+    assert_eq!(
+      config.header_value(),
+      "max-age=31536000; includeSubDomains; preload"
+    );
+    assert_eq!(
+      HstsConfig {
+        max_age: 300,
+        ..HstsConfig::default()
+      }
+      .header_value(),
+      "max-age=300"
+    );
+  }
+}
@@ -0,0 +1,441 @@
+//! アプリケーションログの実装
+//!
+//! ログファイルへ追記し、サイズもしくは日付の変わり目でローテーションする。
+//! 出力形式とモジュール毎のレベルはコンフィグの再読み込みで差し替えられる
+
+use std::{
+  collections::BTreeMap,
+  fs::File,
+  io::Write,
+  path::{Path, PathBuf},
+  sync::OnceLock,
+};
+
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use log::LevelFilter;
+use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
+
+/// ログの出力形式
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+  /// `[時刻 レベル ターゲット req=リクエストID] メッセージ`
+  #[serde(alias = "text")]
+  Text,
+
+  /// 一行一レコードのJSON
+  #[serde(alias = "json")]
+  Json,
+}
+
+/// ログのローテーションについてのコンフィグ
+#[derive(Deserialize, Serialize, Clone)]
+pub struct LogRotationConfig {
+  /// このサイズ(バイト)を超えたらローテーションする
+  pub max_size: Option<u64>,
+
+  /// 日付(UTC)が変わったらローテーションする
+  pub daily: bool,
+
+  /// ローテーション済みのファイルを残す数
+  pub retention: usize,
+}
+impl Default for LogRotationConfig {
+  fn default() -> Self {
+    Self {
+      max_size: Some(16 * 1024 * 1024),
+      daily: true,
+      retention: 14,
+    }
+  }
+}
+
+/// ログについてのコンフィグ(出力先と全体のレベルは`Config`直下)
+#[derive(Deserialize, Serialize, Clone)]
+pub struct LoggingConfig {
+  pub format: LogFormat,
+
+  /// モジュールパス毎のレベル(`"tmdx4_workplace::usersys": "trace"`等)
+  pub modules: BTreeMap<String, LevelFilter>,
+
+  pub rotation: LogRotationConfig,
+}
+impl Default for LoggingConfig {
+  fn default() -> Self {
+    Self {
+      format: LogFormat::Text,
+      modules: BTreeMap::new(),
+      rotation: LogRotationConfig::default(),
+    }
+  }
+}
+
+/// 出力の可否と形式(再読み込みで差し替える部分)
+struct Filter {
+  level: LevelFilter,
+  modules: BTreeMap<String, LevelFilter>,
+  format: LogFormat,
+}
+impl Filter {
+  fn new(config: &crate::config::Config) -> Self {
+    Self {
+      level: config.log_level,
+      modules: config.logging.modules.clone(),
+      format: config.logging.format,
+    }
+  }
+
+  /// 最も長く一致するモジュール指定のレベル、無ければ全体のレベル
+  fn level_for(&self, target: &str) -> LevelFilter {
+    self
+      .modules
+      .iter()
+      .filter(|(module, _)| {
+        target == module.as_str()
+          || target
+            .strip_prefix(module.as_str())
+            .is_some_and(|rest| rest.starts_with("::"))
+      })
+      .max_by_key(|(module, _)| module.len())
+      .map(|(_, level)| *level)
+      .unwrap_or(self.level)
+  }
+
+  /// `log`クレート側で弾いてもらう最大レベル
+  fn max_level(&self) -> LevelFilter {
+    self.modules.values().copied().fold(self.level, Ord::max)
+  }
+}
+
+/// ローテーションしたファイルに付ける日時の形式
+const ROTATED_SUFFIX: &str = "%Y%m%d-%H%M%S%.3f";
+
+/// 追記とローテーションを行うログファイル
+pub(crate) struct RotatingFile {
+  path: PathBuf,
+  file: File,
+  size: u64,
+  opened: NaiveDate,
+  rotation: LogRotationConfig,
+}
+impl RotatingFile {
+  pub(crate) fn open(
+    path: &Path,
+    rotation: LogRotationConfig,
+  ) -> std::io::Result<Self> {
+    let file =
+      File::options().create(true).append(true).open(path)?;
+    let metadata = file.metadata()?;
+    // 追記するファイルの日付は最後に書き込んだ日とする
+    let opened = metadata
+      .modified()
+      .map(|modified| {
+        DateTime::<Utc>::from(modified).date_naive()
+      })
+      .unwrap_or_else(|_| Utc::now().date_naive());
+    Ok(Self {
+      path: path.into(),
+      file,
+      size: metadata.len(),
+      opened,
+      rotation,
+    })
+  }
+
+  pub(crate) fn write_line(
+    &mut self,
+    line: &[u8],
+  ) -> std::io::Result<()> {
+    let today = Utc::now().date_naive();
+    let exceeded = self.rotation.max_size.is_some_and(|max| {
+      self.size > 0 && self.size + line.len() as u64 > max
+    });
+    if exceeded || (self.rotation.daily && today != self.opened)
+    {
+      self.rotate()?;
+    }
+    self.file.write_all(line)?;
+    self.size += line.len() as u64;
+    Ok(())
+  }
+
+  /// 現在のファイルを`<ファイル名>.<日時>`に退避し、古いものを片付ける
+  fn rotate(&mut self) -> std::io::Result<()> {
+    self.file.flush()?;
+    let file_name = self
+      .path
+      .file_name()
+      .map(|name| name.to_string_lossy().into_owned())
+      .unwrap_or_default();
+    let rotated = self.path.with_file_name(format!(
+      "{file_name}.{}",
+      Utc::now().format(ROTATED_SUFFIX)
+    ));
+    std::fs::rename(&self.path, rotated)?;
+    *self = Self::open(&self.path, self.rotation.clone())?;
+
+    let dir = match self.path.parent() {
+      Some(dir) if !dir.as_os_str().is_empty() => dir,
+      _ => Path::new("."),
+    };
+    let prefix = format!("{file_name}.");
+    let mut rotated_files = std::fs::read_dir(dir)?
+      .filter_map(|entry| entry.ok())
+      .filter(|entry| {
+        entry
+          .file_name()
+          .to_string_lossy()
+          .strip_prefix(&prefix)
+          .is_some_and(|suffix| {
+            NaiveDateTime::parse_from_str(suffix, ROTATED_SUFFIX)
+              .is_ok()
+          })
+      })
+      .map(|entry| entry.path())
+      .collect::<Vec<_>>();
+    // 日時の接尾辞なので名前順が古い順
+    rotated_files.sort();
+    let excess = rotated_files
+      .len()
+      .saturating_sub(self.rotation.retention);
+    for path in &rotated_files[..excess] {
+      std::fs::remove_file(path)?;
+    }
+    Ok(())
+  }
+
+  pub(crate) fn flush(&mut self) -> std::io::Result<()> {
+    self.file.flush()
+  }
+
+  pub(crate) fn set_rotation(
+    &mut self,
+    rotation: LogRotationConfig,
+  ) {
+    self.rotation = rotation;
+  }
+}
+
+struct Logger {
+  filter: RwLock<Filter>,
+  file: Mutex<RotatingFile>,
+}
+impl log::Log for Logger {
+  fn enabled(&self, metadata: &log::Metadata) -> bool {
+    metadata.level()
+      <= self.filter.read().level_for(metadata.target())
+  }
+
+  fn log(&self, record: &log::Record) {
+    let format = {
+      let filter = self.filter.read();
+      if record.level() > filter.level_for(record.target()) {
+        return;
+      }
+      filter.format
+    };
+    let timestamp = Utc::now()
+      .to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+    let request_id = crate::request_id::current();
+    let mut line = match format {
+      LogFormat::Text => format!(
+        "[{timestamp} {:<5} {}{}] {}",
+        record.level(),
+        record.target(),
+        request_id
+          .map(|id| format!(" req={id}"))
+          .unwrap_or_default(),
+        record.args()
+      ),
+      LogFormat::Json => serde_json::json!({
+        "time": timestamp,
+        "level": record.level().as_str(),
+        "target": record.target(),
+        "request_id": request_id,
+        "message": record.args().to_string(),
+      })
+      .to_string(),
+    };
+    line.push('\n');
+    if let Err(e) = self.file.lock().write_line(line.as_bytes())
+    {
+      eprintln!("log write error: {e}");
+    }
+  }
+
+  fn flush(&self) {
+    let _ = self.file.lock().flush();
+  }
+}
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+/// コンフィグに従ってログファイルを開き、ロガーを登録する
+pub fn init(
+  config: &crate::config::Config,
+) -> Result<(), Box<dyn std::error::Error>> {
+  let file = RotatingFile::open(
+    Path::new(&config.log_file),
+    config.logging.rotation.clone(),
+  )?;
+  let filter = Filter::new(config);
+  let max_level = filter.max_level();
+  let logger = LOGGER.get_or_init(|| Logger {
+    filter: RwLock::new(filter),
+    file: Mutex::new(file),
+  });
+  log::set_logger(logger)?;
+  log::set_max_level(max_level);
+  Ok(())
+}
+
+/// 再読み込みしたコンフィグのレベル・形式・ローテーション設定を反映する
+/// (出力先のファイルは再起動するまで変わらない)
+pub fn reconfigure(config: &crate::config::Config) {
+  let Some(logger) = LOGGER.get() else {
+    return;
+  };
+  let filter = Filter::new(config);
+  log::set_max_level(filter.max_level());
+  *logger.filter.write() = filter;
+  logger
+    .file
+    .lock()
+    .set_rotation(config.logging.rotation.clone());
+}
+
+/// 書き込み途中のログを吐き出す
+pub fn flush() {
+  log::logger().flush();
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!(
+      "tmdx4-logging-{name}-{}",
+      std::process::id()
+    ));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+  }
+
+  fn rotated(dir: &Path) -> usize {
+    std::fs::read_dir(dir)
+      .unwrap()
+      .filter(|entry| {
+        entry
+          .as_ref()
+          .unwrap()
+          .file_name()
+          .to_string_lossy()
+          .starts_with("app.log.")
+      })
+      .count()
+  }
+
+  #[test]
+  fn the_longest_module_prefix_wins() {
+    let filter = Filter {
+      level: LevelFilter::Info,
+      modules: BTreeMap::from([
+        ("tmdx4".into(), LevelFilter::Warn),
+        ("tmdx4::usersys".into(), LevelFilter::Trace),
+      ]),
+      format: LogFormat::Text,
+    };
+    assert_eq!(
+      filter.level_for("tmdx4::usersys::x"),
+      LevelFilter::Trace
+    );
+    assert_eq!(
+      filter.level_for("tmdx4::mainte"),
+      LevelFilter::Warn
+    );
+    assert_eq!(
+      filter.level_for("tmdx4_other"),
+      LevelFilter::Info
+    );
+    assert_eq!(filter.max_level(), LevelFilter::Trace);
+  }
+
+  #[test]
+  fn rotates_by_size_and_keeps_the_retention() {
+    let dir = test_dir("size");
+    let path = dir.join("app.log");
+    let mut file = RotatingFile::open(
+      &path,
+      LogRotationConfig {
+        max_size: Some(10),
+        daily: false,
+        retention: 2,
+      },
+    )
+    .unwrap();
+    std::fs::write(dir.join("app.log.bak"), b"").unwrap();
+    for _ in 0..5 {
+      file.write_line(b"0123456789\n").unwrap();
+      // ローテーション先の名前はミリ秒単位なので重ならないようにする
+      std::thread::sleep(std::time::Duration::from_millis(5));
+    }
+    file.flush().unwrap();
+    // ローテーションしたもの2つと、名前が似ているだけのもの
+    assert_eq!(rotated(&dir), 3);
+    assert!(dir.join("app.log.bak").exists());
+    assert_eq!(std::fs::read(&path).unwrap(), b"0123456789\n");
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn rotates_when_the_date_changes() {
+    let dir = test_dir("daily");
+    let path = dir.join("app.log");
+    let rotation = LogRotationConfig {
+      max_size: None,
+      daily: true,
+      retention: 14,
+    };
+    let mut file = RotatingFile::open(&path, rotation).unwrap();
+    file.write_line(b"a\n").unwrap();
+    file.write_line(b"b\n").unwrap();
+    assert_eq!(rotated(&dir), 0);
+    file.opened = file.opened.pred_opt().unwrap();
+    file.write_line(b"c\n").unwrap();
+    file.flush().unwrap();
+    assert_eq!(rotated(&dir), 1);
+    assert_eq!(std::fs::read(&path).unwrap(), b"c\n");
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn lines_from_before_a_restart_are_rotated_by_date() {
+    let dir = test_dir("restart");
+    let path = dir.join("app.log");
+    let mut file = File::create(&path).unwrap();
+    file.write_all(b"yesterday\n").unwrap();
+    file
+      .set_modified(
+        std::time::SystemTime::now()
+          - std::time::Duration::from_secs(60 * 60 * 24),
+      )
+      .unwrap();
+    drop(file);
+    let mut file = RotatingFile::open(
+      &path,
+      LogRotationConfig {
+        max_size: None,
+        daily: true,
+        retention: 14,
+      },
+    )
+    .unwrap();
+    file.write_line(b"today\n").unwrap();
+    file.flush().unwrap();
+    assert_eq!(rotated(&dir), 1);
+    assert_eq!(std::fs::read(&path).unwrap(), b"today\n");
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+}
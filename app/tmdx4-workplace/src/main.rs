@@ -1,101 +1,178 @@
-use std::{
-  fs::File, io::BufWriter, net::Ipv4Addr, sync::LazyLock,
-};
+use std::{future::IntoFuture, path::Path, process::ExitCode};
 
+pub mod access_log;
 pub mod bsod;
+pub mod caching;
+pub mod cli;
+pub mod config;
+pub mod error;
+pub mod html;
+pub mod listener;
+pub mod logging;
 pub mod main_page;
 pub mod mainte;
+pub mod preferences;
+pub mod request_id;
+pub mod security_headers;
 pub mod service;
+pub mod shutdown;
+pub mod stylesheet;
+pub mod theme;
 pub mod usersys;
 
-use axum::{Router, http::StatusCode, routing::get};
-use chrono::{DateTime, Utc};
-use env_logger::Target;
-use serde::{Deserialize, Serialize};
-use tokio::net::TcpListener;
+use axum::{
+  Router, http::StatusCode, middleware::from_fn, routing::get,
+};
+use clap::Parser;
+use listener::{BoundListener, PeerAddr};
 
-pub const COMMON_CSS: &'static str =
-  include_str!("styles/common.css");
-pub const MAIN_CSS: &'static str =
-  include_str!("styles/main.css");
+/// sysexits.hのEX_CONFIG
+const EXIT_CONFIG_ERROR: u8 = 78;
 
-#[derive(Deserialize, Serialize)]
-pub struct Config {
-  pub origin_time: DateTime<Utc>,
-  pub listen_port: u16,
-  pub log_file: String,
-  pub maintenance_page: mainte::MaintePageConfig,
-  pub service: service::ServiceConfig,
-}
-impl Default for Config {
-  fn default() -> Self {
-    Self {
-      origin_time: DateTime::parse_from_rfc3339(
-        "2025-01-01T00:00:00.00Z",
-      )
-      .unwrap()
-      .into(),
-      listen_port: 8080,
-      log_file: "tmdx4-workplace.log".into(),
-      maintenance_page: mainte::MaintePageConfig::default(),
-      service: service::ServiceConfig::default(),
+#[tokio::main]
+async fn main() -> ExitCode {
+  let cli = cli::Cli::parse();
+  let result = match cli.command.unwrap_or(cli::Command::Serve) {
+    cli::Command::Serve => serve(&cli.config).await,
+    cli::Command::User(command) => {
+      cli::user::run(command, &cli.config)
+    }
+    cli::Command::Article(command) => {
+      cli::article::run(command, &cli.config)
+    }
+    cli::Command::Config(command) => {
+      cli::config::run(command, &cli.config)
+    }
+  };
+  match result {
+    Ok(()) => ExitCode::SUCCESS,
+    Err(e) => {
+      eprintln!("{e}");
+      if e.is::<config::ConfigError>() {
+        ExitCode::from(EXIT_CONFIG_ERROR)
+      } else {
+        ExitCode::FAILURE
+      }
     }
   }
 }
 
-const CONFIG_FILENAME: &'static str = "config.json";
-static CONFIG: LazyLock<Config> = LazyLock::new(|| {
-  let fp = match std::fs::File::open(CONFIG_FILENAME) {
-    Ok(fp) => std::io::BufReader::new(fp),
-    Err(e) => match e.kind() {
-      std::io::ErrorKind::NotFound => {
-        let mut fp = std::io::BufWriter::new(
-          std::fs::File::create(CONFIG_FILENAME).unwrap(),
-        );
-        eprintln!(
-          "コンフィグファイルが存在しない為、初期設定ファイルを生成します"
-        );
-        <BufWriter<std::fs::File> as std::io::Write>::write(
-          &mut fp,
-          &serde_json::to_vec_pretty(&Config::default())
-            .unwrap(),
+async fn serve(
+  config_path: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+  config::init(config_path)?;
+  logging::init(&config::get())?;
+  access_log::init(&config::get())?;
+  config::spawn_reloader(config_path.to_path_buf())?;
+  service::init(&config::get().service)?;
+  preferences::init(&config::get().preferences)?;
+  theme::reconfigure(&config::get());
+  let app = Router::new()
+    .route("/", get(main_page::main_page))
+    .route(
+      "/announcements",
+      get(main_page::archive::archive_page),
+    )
+    .nest("/mainte", mainte::mainte_serve())
+    .nest("/static", stylesheet::stylesheet_serve())
+    .fallback(async || bsod::Bsod::new(StatusCode::NOT_FOUND))
+    .method_not_allowed_fallback(async || {
+      bsod::Bsod::new(StatusCode::METHOD_NOT_ALLOWED)
+    })
+    .layer(tower_http::catch_panic::CatchPanicLayer::custom(
+      bsod::panic_response,
+    ))
+    .layer(from_fn(bsod::negotiate))
+    .layer(
+      tower_http::compression::CompressionLayer::new()
+        .compress_when(caching::compress_when()),
+    )
+    .layer(from_fn(caching::caching))
+    .layer(from_fn(security_headers::security_headers))
+    .layer(from_fn(listener::tls::hsts))
+    .layer(from_fn(access_log::access_log))
+    .layer(from_fn(request_id::request_id));
+  let listeners = listener::bind_all(&config::get()).await?;
+
+  let (stop_tx, stop_rx) = tokio::sync::watch::channel(false);
+  let mut servers = tokio::task::JoinSet::new();
+  let mut socket_files = Vec::new();
+  for bound in listeners {
+    log::info!("listening on {bound}");
+    let mut stop_rx = stop_rx.clone();
+    let stopped = async move {
+      let _ = stop_rx.wait_for(|stop| *stop).await;
+    };
+    let make_service = app
+      .clone()
+      .into_make_service_with_connect_info::<PeerAddr>();
+    match bound {
+      BoundListener::Tcp(listener) => servers.spawn(
+        axum::serve(listener, make_service)
+          .with_graceful_shutdown(stopped)
+          .into_future(),
+      ),
+      BoundListener::Tls(listener) => servers.spawn(
+        axum::serve(listener, make_service)
+          .with_graceful_shutdown(stopped)
+          .into_future(),
+      ),
+      BoundListener::Redirect(listener, https_port) => servers
+        .spawn(
+          axum::serve(
+            listener,
+            listener::tls::redirect_router(https_port)
+              .layer(from_fn(access_log::access_log))
+              .layer(from_fn(request_id::request_id))
+              .into_make_service_with_connect_info::<PeerAddr>(),
+          )
+          .with_graceful_shutdown(stopped)
+          .into_future(),
+        ),
+      BoundListener::Unix(listener, socket_file) => {
+        socket_files.extend(socket_file);
+        servers.spawn(
+          axum::serve(listener, make_service)
+            .with_graceful_shutdown(stopped)
+            .into_future(),
         )
-        .unwrap();
-        eprintln!(
-          "内容を適宜なものとして、再度アプリケーションの起動をお願いします。"
-        );
-        panic!();
       }
-      _ => panic!("{e}"),
-    },
+    };
+  }
+
+  // 終了要求か、いずれかの待ち受けが止まったら終了処理に入る
+  let failure = tokio::select! {
+    _ = shutdown::signal_received() => None,
+    Some(r) = servers.join_next() => Some(match r {
+      Ok(Ok(())) => "listener stopped unexpectedly".to_string(),
+      Ok(Err(e)) => format!("listener error: {e}"),
+      Err(e) => format!("listener task failed: {e}"),
+    }),
   };
-  serde_json::from_reader(fp).unwrap()
-});
+  if let Some(failure) = &failure {
+    log::error!("{failure}");
+  }
+  log::info!("stop accepting, draining in-flight requests");
+  let _ = stop_tx.send(true);
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-  env_logger::builder()
-    .filter_level(if cfg!(debug_assertions) {
-      log::LevelFilter::Debug
-    } else {
-      log::LevelFilter::Info
+  // 期限を過ぎても終わらないリクエストは打ち切る
+  let drained =
+    tokio::time::timeout(shutdown::drain_timeout(), async {
+      while servers.join_next().await.is_some() {}
     })
-    .target(Target::Pipe({
-      let fp = File::create("./tmdx4-workplace.log")?;
-      Box::from(BufWriter::new(fp))
-    }))
-    .build();
-  let app = Router::new()
-    .route("/", get(move |q| main_page::main_page(q)))
-    .nest("/mainte", mainte::mainte_serve())
-    .fallback(async || {
-      bsod::bsod(StatusCode::NOT_FOUND, None, None)
-    });
-  let listener = TcpListener::bind((
-    Ipv4Addr::from([0, 0, 0, 0]),
-    CONFIG.listen_port,
-  ))
-  .await?;
-  axum::serve(listener, app).await?;
-  Ok(())
+    .await;
+  if drained.is_err() {
+    log::warn!(
+      "drain timeout exceeded, dropping remaining connections"
+    );
+    servers.abort_all();
+  }
+  for socket_file in socket_files {
+    let _ = std::fs::remove_file(socket_file);
+  }
+  shutdown::finalize();
+  match failure {
+    Some(failure) => Err(Box::from(failure)),
+    None => Ok(()),
+  }
 }
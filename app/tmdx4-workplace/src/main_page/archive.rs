@@ -0,0 +1,57 @@
+//! お知らせの一覧ページの実装
+
+use askama::Template;
+use axum::{
+  http::header,
+  response::{Html, IntoResponse},
+};
+use chrono::Utc;
+
+use super::frame::Frame;
+use crate::{
+  error::AppError, html::Escaped, preferences::ViewPrefs,
+  service::announcement::announcements,
+};
+
+/// 一覧の行
+struct ArchiveRow {
+  date: String,
+  text: Escaped<String>,
+  active: bool,
+}
+
+#[derive(Template)]
+#[template(path = "announcements.html")]
+struct ArchivePage<'a> {
+  frame: Frame<'a>,
+  rows: Vec<ArchiveRow>,
+}
+
+/// 掲載を始めたお知らせを新しい順に並べる
+pub async fn archive_page(
+  prefs: ViewPrefs,
+) -> Result<impl IntoResponse, AppError> {
+  let config = crate::config::get();
+  let config = &config.service.announcements;
+  let now = Utc::now();
+  let rows = announcements()
+    .read()
+    .archive(now)
+    .into_iter()
+    .map(|a| ArchiveRow {
+      date: config.format_local(&a.starts_at),
+      text: Escaped(a.text.clone()),
+      active: a.is_active(now),
+    })
+    .collect();
+  let page = ArchivePage {
+    frame: Frame::new(&prefs),
+    rows,
+  }
+  .render()?;
+  Ok((
+    prefs.set_cookie(),
+    [(header::VARY, "Cookie, Sec-CH-Prefers-Color-Scheme")],
+    Html(page),
+  ))
+}
@@ -0,0 +1,359 @@
+//! デスクトップ(メインウィンドウの上に開くウィンドウ)の実装
+//!
+//! 記事・ギャラリー・このサイトについてのウィンドウを開ける。
+//! 開いているウィンドウは表示設定([`super::MainArgs`])に奥から順に並べて持ち、
+//! スタートメニュー・タスクバー・タイトル行のリンク(`?open=`・`?hide=`・`?close=`)で操作するので、
+//! JavaScriptを使わずに動く
+
+use std::{
+  fmt::{Display, Formatter, Result as FmtResult},
+  path::Path,
+  str::FromStr,
+};
+
+use serde::{Deserialize, Serialize, de::Error as _};
+
+use crate::{
+  html::Escaped,
+  service::article::{ArticleID, articles},
+};
+
+/// 同時に開けるウィンドウの数(超えたら最も奥のものを閉じる)
+pub const MAX_WINDOWS: usize = 8;
+
+/// スタートメニューに並べる記事の数
+const START_MENU_ARTICLES: usize = 10;
+
+/// ギャラリーに並べる画像を置くディレクトリ(`assets_rootpath`からの相対)
+const GALLERY_DIR: &str = "img/gallery";
+
+/// ウィンドウの種類
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WindowId {
+  /// ツナマヨの屋根裏部屋について
+  About,
+  /// ギャラリー
+  Gallery,
+  /// 記事
+  Article(ArticleID),
+}
+impl Display for WindowId {
+  fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+    match self {
+      Self::About => f.write_str("about"),
+      Self::Gallery => f.write_str("gallery"),
+      Self::Article(id) => write!(f, "article-{id}"),
+    }
+  }
+}
+impl FromStr for WindowId {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s.trim() {
+      "about" => Ok(Self::About),
+      "gallery" => Ok(Self::Gallery),
+      s => s
+        .strip_prefix("article-")
+        .and_then(|id| id.parse().ok())
+        .map(Self::Article)
+        .ok_or_else(|| format!("unknown window: {s}")),
+    }
+  }
+}
+impl Serialize for WindowId {
+  fn serialize<S>(
+    &self,
+    serializer: S,
+  ) -> Result<S::Ok, S::Error>
+  where
+    S: serde::Serializer,
+  {
+    serializer.collect_str(self)
+  }
+}
+impl<'de> Deserialize<'de> for WindowId {
+  fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+  where
+    D: serde::Deserializer<'de>,
+  {
+    String::deserialize(deserializer)?
+      .parse()
+      .map_err(D::Error::custom)
+  }
+}
+
+/// 開いているウィンドウ
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OpenWindow {
+  pub id: WindowId,
+  /// 最小化してタスクバーにだけ出しているか
+  pub minimized: bool,
+}
+
+/// 開いているウィンドウ(奥から順)
+/// 保存・送信の形は`about,~article-3`のようにカンマで区切ったもので、
+/// 最小化したものには`~`を付ける
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Windows(Vec<OpenWindow>);
+impl Windows {
+  /// ウィンドウを開いて最も手前にする(開いていれば元に戻して手前に移す)
+  pub fn open(&mut self, id: WindowId) {
+    self.0.retain(|w| w.id != id);
+    self.0.push(OpenWindow {
+      id,
+      minimized: false,
+    });
+    if self.0.len() > MAX_WINDOWS {
+      self.0.remove(0);
+    }
+  }
+
+  /// 開いているウィンドウを最小化する
+  pub fn minimize(&mut self, id: WindowId) {
+    for w in self.0.iter_mut().filter(|w| w.id == id) {
+      w.minimized = true;
+    }
+  }
+
+  pub fn close(&mut self, id: WindowId) {
+    self.0.retain(|w| w.id != id);
+  }
+
+  pub fn iter(&self) -> impl Iterator<Item = OpenWindow> + '_ {
+    self.0.iter().copied()
+  }
+
+  /// 最も手前に表示しているウィンドウ
+  pub fn front(&self) -> Option<WindowId> {
+    self.0.iter().rev().find(|w| !w.minimized).map(|w| w.id)
+  }
+}
+impl Display for Windows {
+  fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+    for (i, w) in self.0.iter().enumerate() {
+      if i > 0 {
+        f.write_str(",")?;
+      }
+      if w.minimized {
+        f.write_str("~")?;
+      }
+      w.id.fmt(f)?;
+    }
+    Ok(())
+  }
+}
+impl Serialize for Windows {
+  fn serialize<S>(
+    &self,
+    serializer: S,
+  ) -> Result<S::Ok, S::Error>
+  where
+    S: serde::Serializer,
+  {
+    serializer.collect_str(self)
+  }
+}
+impl<'de> Deserialize<'de> for Windows {
+  fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+  where
+    D: serde::Deserializer<'de>,
+  {
+    let mut windows = Self::default();
+    for entry in String::deserialize(deserializer)?
+      .split(',')
+      .map(str::trim)
+      .filter(|entry| !entry.is_empty())
+    {
+      let (minimized, id) = match entry.strip_prefix('~') {
+        Some(id) => (true, id),
+        None => (false, entry),
+      };
+      let id = id.parse().map_err(D::Error::custom)?;
+      windows.open(id);
+      if minimized {
+        windows.minimize(id);
+      }
+    }
+    Ok(windows)
+  }
+}
+
+/// ウィンドウの中身
+#[derive(Debug)]
+pub enum WindowContent {
+  About,
+  /// 画像のファイル名
+  Gallery(Vec<Escaped<String>>),
+  /// 記事の本文
+  Article(Escaped<String>),
+  /// 削除された記事等
+  Missing,
+}
+
+/// 表示するウィンドウ
+#[derive(Debug)]
+pub struct WindowView {
+  pub id: String,
+  pub title: Escaped<String>,
+  pub icon: &'static str,
+  /// 最も手前にあるか
+  pub front: bool,
+  /// 最小化しているか(タスクバーにだけ出す)
+  pub minimized: bool,
+  pub content: WindowContent,
+}
+impl WindowView {
+  pub fn list(windows: &Windows) -> Vec<Self> {
+    let front = windows.front();
+    windows
+      .iter()
+      .map(|OpenWindow { id, minimized }| {
+        let (title, icon, content) = match id {
+          WindowId::About => (
+            "ツナマヨの屋根裏部屋について".to_string(),
+            "？",
+            WindowContent::About,
+          ),
+          WindowId::Gallery => (
+            "ギャラリー".into(),
+            "▣",
+            WindowContent::Gallery(gallery_images()),
+          ),
+          WindowId::Article(aid) => {
+            match articles().read().request(&aid) {
+              Some(article) => (
+                article.title().into(),
+                "▤",
+                WindowContent::Article(Escaped(
+                  article.body().into(),
+                )),
+              ),
+              None => (
+                format!("記事{aid}"),
+                "▤",
+                WindowContent::Missing,
+              ),
+            }
+          }
+        };
+        Self {
+          id: id.to_string(),
+          title: Escaped(title),
+          icon,
+          front: front == Some(id),
+          minimized,
+          content,
+        }
+      })
+      .collect()
+  }
+}
+
+/// スタートメニューの記事の項目
+#[derive(Debug)]
+pub struct StartMenuArticle {
+  pub id: String,
+  pub title: Escaped<String>,
+}
+impl StartMenuArticle {
+  /// 新しい記事から順に並べる
+  pub fn list() -> Vec<Self> {
+    let articles = articles().read();
+    let mut list = articles.iter().collect::<Vec<_>>();
+    list.sort_by_key(|article| std::cmp::Reverse(article.id()));
+    list
+      .into_iter()
+      .take(START_MENU_ARTICLES)
+      .map(|article| Self {
+        id: WindowId::Article(article.id()).to_string(),
+        title: Escaped(article.title().into()),
+      })
+      .collect()
+  }
+}
+
+/// ギャラリーの画像(ファイル名順)
+/// URLにそのまま埋め込めるファイル名のものだけを並べる
+fn gallery_images() -> Vec<Escaped<String>> {
+  let config = crate::config::get();
+  let dir = Path::new(&config.service.assets.assets_rootpath)
+    .join(GALLERY_DIR);
+  let Ok(entries) = std::fs::read_dir(dir) else {
+    return Vec::new();
+  };
+  let mut images = entries
+    .filter_map(|entry| {
+      entry.ok()?.file_name().into_string().ok()
+    })
+    .filter(|name| {
+      name.bytes().all(|b| {
+        b.is_ascii_alphanumeric()
+          || matches!(b, b'.' | b'_' | b'-')
+      }) && name.rsplit_once('.').is_some_and(|(_, ext)| {
+        matches!(
+          ext.to_ascii_lowercase().as_str(),
+          "webp" | "png" | "jpg" | "jpeg" | "gif"
+        )
+      })
+    })
+    .collect::<Vec<_>>();
+  images.sort();
+  images.into_iter().map(Escaped).collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn windows(s: &str) -> Result<Windows, serde_json::Error> {
+    serde_json::from_value(serde_json::Value::String(s.into()))
+  }
+
+  #[test]
+  fn opening_brings_a_window_to_the_front() {
+    let mut w = windows("about,gallery,article-3").unwrap();
+    w.open(WindowId::About);
+    assert_eq!(w.to_string(), "gallery,article-3,about");
+    w.close(WindowId::Gallery);
+    assert_eq!(
+      serde_json::to_string(&w).unwrap(),
+      "\"article-3,about\""
+    );
+  }
+
+  #[test]
+  fn minimized_windows_stay_in_place_until_reopened() {
+    let mut w = windows("about,gallery").unwrap();
+    w.minimize(WindowId::Gallery);
+    assert_eq!(w.to_string(), "about,~gallery");
+    assert_eq!(w.front(), Some(WindowId::About));
+    w.minimize(WindowId::About);
+    assert_eq!(w.front(), None);
+    assert_eq!(windows(&w.to_string()).unwrap(), w);
+    w.open(WindowId::About);
+    assert_eq!(w.to_string(), "~gallery,about");
+    assert!(windows("~").is_err());
+  }
+
+  #[test]
+  fn the_number_of_windows_is_bounded() {
+    let mut w = Windows::default();
+    for _ in 0..MAX_WINDOWS {
+      w.open(WindowId::About);
+    }
+    assert_eq!(w.iter().count(), 1);
+    for id in 0..=MAX_WINDOWS {
+      w.open(WindowId::Article(id.to_string().parse().unwrap()));
+    }
+    assert_eq!(w.iter().count(), MAX_WINDOWS);
+    assert!(!w.iter().any(|w| w.id == WindowId::About));
+  }
+
+  #[test]
+  fn unknown_windows_are_rejected() {
+    assert!(windows("").unwrap().iter().next().is_none());
+    assert!(windows("about,desktop").is_err());
+    assert!(windows("article-x").is_err());
+  }
+}
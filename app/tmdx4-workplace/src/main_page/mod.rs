@@ -1,22 +1,197 @@
 //! メインページの各種実装
 
-use crate::{COMMON_CSS, MAIN_CSS};
+use std::{
+  sync::{
+    LazyLock,
+    atomic::{AtomicU64, Ordering},
+  },
+  time::SystemTime,
+};
+
 use axum::{
-  extract::Query,
+  http::{HeaderMap, HeaderName, header},
   response::{Html, IntoResponse},
 };
+use chrono::{FixedOffset, Timelike, Utc};
 use serde::{Deserialize, Serialize};
+use sha3::{Digest, Sha3_256};
+
+use crate::{
+  error::AppError,
+  preferences::ViewPrefs,
+  service::{announcement::announcements, counter::Visit},
+};
+
+pub mod archive;
+pub mod desktop;
 pub mod frame;
 
-#[derive(
-  Default, Debug, Clone, Copy, Serialize, Deserialize,
-)]
+/// 起動した時刻(ページの内容の版に含める)
+static STARTED_AT: LazyLock<u128> = LazyLock::new(|| {
+  SystemTime::now()
+    .duration_since(SystemTime::UNIX_EPOCH)
+    .map(|d| d.as_nanos())
+    .unwrap_or_default()
+});
+
+/// 訪問者の配色の好みを伝えるクライアントヒント
+static SEC_CH_PREFERS_COLOR_SCHEME: HeaderName =
+  HeaderName::from_static("sec-ch-prefers-color-scheme");
+
+/// 送ってほしいクライアントヒントを伝えるヘッダ
+static ACCEPT_CH: HeaderName =
+  HeaderName::from_static("accept-ch");
+
+/// 自動の表示モードについてのコンフィグ
+#[derive(Deserialize, Serialize, Clone)]
+pub struct ViewModeConfig {
+  /// 時刻で決める時のタイムゾーン(UTCからのオフセット、`+09:00`等)
+  pub utc_offset: String,
+
+  /// 昼間モードに切り替える時(0〜23)
+  pub day_start_hour: u32,
+
+  /// 夜間モードに切り替える時(0〜23)
+  pub night_start_hour: u32,
+}
+impl Default for ViewModeConfig {
+  fn default() -> Self {
+    Self {
+      utc_offset: "+09:00".into(),
+      day_start_hour: 6,
+      night_start_hour: 18,
+    }
+  }
+}
+impl ViewModeConfig {
+  /// コンフィグの内容を検証し、見つかった問題を`problems`に追加する
+  pub fn validate(&self, problems: &mut Vec<String>) {
+    if self.utc_offset.parse::<FixedOffset>().is_err() {
+      problems.push(format!(
+        "view_mode.utc_offset: {}は+09:00のようなオフセットではありません",
+        self.utc_offset
+      ));
+    }
+    for (key, hour) in [
+      ("day_start_hour", self.day_start_hour),
+      ("night_start_hour", self.night_start_hour),
+    ] {
+      if hour > 23 {
+        problems
+          .push(format!("view_mode.{key}: 0〜23にしてください"));
+      }
+    }
+    if self.day_start_hour == self.night_start_hour {
+      problems.push(
+        "view_mode: day_start_hourとnight_start_hourは別の時にしてください"
+          .into(),
+      );
+    }
+  }
+
+  /// その時(0〜23)が昼間か
+  fn is_daytime(&self, hour: u32) -> bool {
+    let (day, night) =
+      (self.day_start_hour, self.night_start_hour);
+    if day < night {
+      (day..night).contains(&hour)
+    } else {
+      !(night..day).contains(&hour)
+    }
+  }
+}
+
+/// ページの内容に関わるデータの更新回数
+static CONTENT_REVISION: AtomicU64 = AtomicU64::new(0);
+
+/// ページの内容が変わったことを記録し、以後のETagを変える
+pub fn bump_content_version() {
+  CONTENT_REVISION.fetch_add(1, Ordering::Relaxed);
+}
+
+/// 表示モード(テーマの選択)
+/// 保存・送信の形はテーマのIDで、`auto`は自動切替
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
 pub enum ViewMode {
+  /// 訪問者の環境もしくはサーバの時刻で明るいテーマと暗いテーマを切り替える
   #[default]
-  #[serde(alias = "daytime")]
-  DayTime,
-  #[serde(alias = "night")]
-  Night,
+  Auto,
+  Theme(String),
+}
+impl ViewMode {
+  /// 表示するテーマのIDに決める(無くなったテーマは`Auto`として扱う)
+  /// `Sec-CH-Prefers-Color-Scheme`があればそれに従い、無ければサーバの時刻で決める
+  pub fn resolve(&self, headers: &HeaderMap) -> String {
+    let themes = crate::theme::get();
+    if let Self::Theme(id) = self
+      && themes.get(id).is_some()
+    {
+      return id.clone();
+    }
+    let dark = match headers
+      .get(&SEC_CH_PREFERS_COLOR_SCHEME)
+      .and_then(|v| v.to_str().ok())
+      .map(|v| v.trim().trim_matches('"'))
+    {
+      Some("dark") => true,
+      Some("light") => false,
+      _ => {
+        let config = crate::config::get();
+        let config = &config.view_mode;
+        let offset = config
+          .utc_offset
+          .parse::<FixedOffset>()
+          .unwrap_or(FixedOffset::east_opt(0).unwrap());
+        !config
+          .is_daytime(Utc::now().with_timezone(&offset).hour())
+      }
+    };
+    themes.auto(dark).into()
+  }
+
+  /// 表示設定のフォームで選ばれた表示モード
+  /// 読み込んでいるテーマでなければ`None`とし、クッキーにもETagにも入れない
+  /// (保存済みのクッキーは無くなったテーマも含めて[`From`]で読む)
+  pub fn selected(id: &str) -> Option<Self> {
+    match Self::from(id) {
+      Self::Theme(id)
+        if crate::theme::get().get(&id).is_none() =>
+      {
+        None
+      }
+      mode => Some(mode),
+    }
+  }
+}
+impl From<&str> for ViewMode {
+  /// 以前の`DayTime`・`Night`等も読めるよう大文字小文字を区別しない
+  fn from(id: &str) -> Self {
+    let id = id.trim().to_ascii_lowercase();
+    if id == crate::theme::AUTO {
+      Self::Auto
+    } else {
+      Self::Theme(id)
+    }
+  }
+}
+impl Serialize for ViewMode {
+  fn serialize<S>(
+    &self,
+    serializer: S,
+  ) -> Result<S::Ok, S::Error>
+  where
+    S: serde::Serializer,
+  {
+    serializer.collect_str(self)
+  }
+}
+impl<'de> Deserialize<'de> for ViewMode {
+  fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+  where
+    D: serde::Deserializer<'de>,
+  {
+    Ok(Self::from(String::deserialize(deserializer)?.as_str()))
+  }
 }
 impl std::fmt::Display for ViewMode {
   fn fmt(
@@ -24,14 +199,21 @@ impl std::fmt::Display for ViewMode {
     f: &mut std::fmt::Formatter<'_>,
   ) -> std::fmt::Result {
     f.write_str(match self {
-      ViewMode::DayTime => "daytime",
-      ViewMode::Night => "night",
+      ViewMode::Auto => crate::theme::AUTO,
+      ViewMode::Theme(id) => id,
     })
   }
 }
 
-#[derive(Debug, Clone, Copy, Serialize)]
+#[derive(
+  Debug, Clone, Copy, PartialEq, Eq, Serialize, Default,
+)]
 pub struct IsSelected(bool);
+impl IsSelected {
+  pub fn is_selected(self) -> bool {
+    self.0
+  }
+}
 pub struct IsSelectedVisitor;
 impl<'de> serde::de::Visitor<'de> for IsSelectedVisitor {
   type Value = IsSelected;
@@ -52,18 +234,20 @@ impl<'de> serde::de::Visitor<'de> for IsSelectedVisitor {
       _ => IsSelected(false),
     })
   }
+  /// 保存した設定(JSON)からの読み込み
+  fn visit_bool<E>(self, v: bool) -> Result<Self::Value, E>
+  where
+    E: serde::de::Error,
+  {
+    Ok(IsSelected(v))
+  }
 }
 impl<'de> serde::Deserialize<'de> for IsSelected {
   fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
   where
     D: serde::Deserializer<'de>,
   {
-    deserializer.deserialize_str(IsSelectedVisitor)
-  }
-}
-impl Default for IsSelected {
-  fn default() -> Self {
-    Self(false)
+    deserializer.deserialize_any(IsSelectedVisitor)
   }
 }
 impl std::fmt::Display for IsSelected {
@@ -78,12 +262,20 @@ impl std::fmt::Display for IsSelected {
   }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(
+  Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default,
+)]
 pub struct MainArgs {
   #[serde(alias = "view-mode", default)]
   pub view_mode: ViewMode,
   #[serde(default)]
   pub maximize: IsSelected,
+  /// メインウィンドウを最小化してタスクバーにだけ出す
+  #[serde(default)]
+  pub minimize: IsSelected,
+  /// メインウィンドウを閉じる(スタートメニューから開き直す)
+  #[serde(default)]
+  pub closed: IsSelected,
   #[serde(default)]
   pub noheader: IsSelected,
   #[serde(default)]
@@ -92,12 +284,139 @@ pub struct MainArgs {
   pub invframe: IsSelected,
   #[serde(default)]
   pub noframe: IsSelected,
+  /// モバイルでサイドフレームをメインの内容と入れ替えて表示する
+  #[serde(default)]
+  pub toggleframe: IsSelected,
+  /// デスクトップに開いているウィンドウ
+  #[serde(default)]
+  pub windows: desktop::Windows,
+}
+
+impl MainArgs {
+  /// 生成するページの弱いETag
+  /// 同じ引数・表示するテーマ・カウンタの数・掲載中のお知らせ・ウィンドウの中身で
+  /// 内容の版とスタイルシートも同じなら、CSPのnonceを除いて同じページになる。
+  /// nonceの分だけバイト列は異なるので強いETagにはしない
+  pub fn etag(
+    &self,
+    theme: &str,
+    visit: &Visit,
+    announcements: &[u64],
+    desktop: &str,
+  ) -> String {
+    let mut hasher = Sha3_256::new();
+    hasher.update(env!("CARGO_PKG_VERSION"));
+    hasher.update(STARTED_AT.to_le_bytes());
+    hasher.update(
+      CONTENT_REVISION.load(Ordering::Relaxed).to_le_bytes(),
+    );
+    for sheet in crate::stylesheet::Stylesheet::ALL {
+      hasher.update(crate::stylesheet::href(sheet));
+    }
+    hasher.update(
+      serde_json::to_vec(self)
+        .expect("MainArgs must be serializable"),
+    );
+    hasher.update(theme);
+    for count in [visit.number, visit.total, visit.daily] {
+      hasher.update(count.to_le_bytes());
+    }
+    for id in announcements {
+      hasher.update(id.to_le_bytes());
+    }
+    hasher.update(desktop);
+    format!("W/\"{}\"", hex::encode(&hasher.finalize()[..16]))
+  }
 }
 
 pub async fn main_page(
-  Query(mq): Query<MainArgs>,
-) -> impl IntoResponse {
+  prefs: ViewPrefs,
+  visit: Visit,
+) -> Result<impl IntoResponse, AppError> {
+  let config = crate::config::get();
+  let (ids, announcements): (Vec<_>, Vec<_>) = announcements()
+    .read()
+    .active(Utc::now())
+    .into_iter()
+    .take(config.service.announcements.marquee_max)
+    .map(|a| (a.id, a.text.clone()))
+    .unzip();
+  let frame = frame::Frame::new(&prefs);
+  let etag = prefs.args.etag(
+    &prefs.theme,
+    &visit,
+    &ids,
+    &frame.fingerprint(),
+  );
   let mut buffer = String::new();
-  frame::gen_frame(&mut buffer, &mq).unwrap();
-  Html(buffer)
+  frame::gen_frame(&mut buffer, frame, visit, announcements)?;
+  Ok((
+    prefs.set_cookie(),
+    [
+      (header::ETAG, etag),
+      (ACCEPT_CH.clone(), "Sec-CH-Prefers-Color-Scheme".into()),
+      (
+        header::VARY,
+        "Cookie, Sec-CH-Prefers-Color-Scheme".into(),
+      ),
+    ],
+    Html(buffer),
+  ))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn auto_follows_the_client_hint() {
+    let mut headers = HeaderMap::new();
+    headers.insert(
+      SEC_CH_PREFERS_COLOR_SCHEME.clone(),
+      "\"dark\"".parse().unwrap(),
+    );
+    assert_eq!(ViewMode::Auto.resolve(&headers), "night");
+    assert_eq!(
+      ViewMode::from("daytime").resolve(&headers),
+      "daytime"
+    );
+    assert_eq!(
+      ViewMode::from("removed-theme").resolve(&headers),
+      "night"
+    );
+  }
+
+  #[test]
+  fn auto_follows_the_clock() {
+    let config = ViewModeConfig::default();
+    assert!(!config.is_daytime(5));
+    assert!(config.is_daytime(6));
+    assert!(!config.is_daytime(18));
+    let config = ViewModeConfig {
+      day_start_hour: 20,
+      night_start_hour: 4,
+      ..config
+    };
+    assert!(config.is_daytime(23));
+    assert!(config.is_daytime(2));
+    assert!(!config.is_daytime(12));
+  }
+
+  #[test]
+  fn saved_view_modes_stay_readable() {
+    for (saved, mode) in [
+      ("\"Auto\"", ViewMode::Auto),
+      ("\"DayTime\"", ViewMode::from("daytime")),
+      ("\"Night\"", ViewMode::Theme("night".into())),
+    ] {
+      assert_eq!(
+        serde_json::from_str::<ViewMode>(saved).unwrap(),
+        mode
+      );
+    }
+    assert_eq!(
+      serde_json::to_string(&ViewMode::Auto).unwrap(),
+      "\"auto\""
+    );
+  }
 }
@@ -1,19 +1,19 @@
 //! メンテナンスページの実装
 
-use std::path::PathBuf;
-
 use axum::{
   Form, Router,
-  http::StatusCode,
   response::{Html, IntoResponse},
   routing::post,
 };
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
-use crate::usersys;
+use crate::{
+  error::AppError,
+  service::menu::{MenuItem, MenuKind},
+  usersys,
+};
 pub mod page_gen;
-pub const MAINTE_CSS: &'static str =
-  include_str!("../styles/mainte.css");
 
 #[derive(Deserialize, Serialize)]
 pub struct MaintePageConfig {
@@ -59,21 +59,21 @@ impl Default for MaintePageConfig {
 }
 
 fn default_user_check() {
+  let config = crate::config::get();
+  let config = &config.maintenance_page;
   if !usersys::UserData::<()>::check_users_exist(
-    &crate::CONFIG.maintenance_page.usersys_config,
+    &config.usersys_config,
   )
   .unwrap()
   {
     let default_user = usersys::UserData::new(
-      &crate::CONFIG.maintenance_page.initial_username,
-      &crate::CONFIG.maintenance_page.initial_pswd,
+      &config.initial_username,
+      &config.initial_pswd,
       None::<()>,
-      &crate::CONFIG.maintenance_page.usersys_config,
+      &config.usersys_config,
     )
     .unwrap();
-    default_user
-      .save(&crate::CONFIG.maintenance_page.usersys_config)
-      .unwrap();
+    default_user.save(&config.usersys_config).unwrap();
   }
 }
 
@@ -96,6 +96,34 @@ struct MaintePageForm {
   new_password: Option<String>,
   #[serde(alias = "new-password-verify")]
   new_password_verify: Option<String>,
+  #[serde(alias = "announce-text")]
+  announce_text: Option<String>,
+  #[serde(alias = "announce-start")]
+  announce_start: Option<String>,
+  #[serde(alias = "announce-end")]
+  announce_end: Option<String>,
+  #[serde(alias = "announce-priority")]
+  announce_priority: Option<String>,
+  #[serde(alias = "announce-remove")]
+  announce_remove: Option<String>,
+  #[serde(alias = "menu-id")]
+  menu_id: Option<String>,
+  #[serde(alias = "menu-kind")]
+  menu_kind: Option<String>,
+  #[serde(alias = "menu-parent")]
+  menu_parent: Option<String>,
+  #[serde(alias = "menu-order")]
+  menu_order: Option<String>,
+  #[serde(alias = "menu-label")]
+  menu_label: Option<String>,
+  #[serde(alias = "menu-url")]
+  menu_url: Option<String>,
+  #[serde(alias = "menu-icon")]
+  menu_icon: Option<String>,
+  #[serde(alias = "menu-external")]
+  menu_external: Option<String>,
+  #[serde(alias = "menu-remove")]
+  menu_remove: Option<String>,
 }
 
 enum ChangeUserDataMode<'a> {
@@ -143,7 +171,9 @@ impl<'a> From<&'a MaintePageForm> for ChangeUserDataMode<'a> {
             } else {
               if crate::usersys::UserData::<()>::check_exist(
                 new_username,
-                &crate::CONFIG.maintenance_page.usersys_config,
+                &crate::config::get()
+                  .maintenance_page
+                  .usersys_config,
               )
               .unwrap()
               {
@@ -154,7 +184,7 @@ impl<'a> From<&'a MaintePageForm> for ChangeUserDataMode<'a> {
           }
           None => None,
         };
-        if crate::CONFIG.maintenance_page.pswd_len_min
+        if crate::config::get().maintenance_page.pswd_len_min
           <= new_password.len()
         {
           match new_username {
@@ -175,11 +205,148 @@ impl<'a> From<&'a MaintePageForm> for ChangeUserDataMode<'a> {
   }
 }
 
+/// お知らせの編集
+enum AnnouncementEdit<'a> {
+  Add {
+    text: &'a str,
+    starts_at: DateTime<Utc>,
+    ends_at: Option<DateTime<Utc>>,
+    priority: i32,
+  },
+  Remove(u64),
+  Invalid(&'static str),
+  Nop,
+}
+impl<'a> From<&'a MaintePageForm> for AnnouncementEdit<'a> {
+  fn from(form: &'a MaintePageForm) -> Self {
+    let non_empty = |field: &'a Option<String>| {
+      field.as_deref().map(str::trim).filter(|s| !s.is_empty())
+    };
+    if let Some(id) = non_empty(&form.announce_remove) {
+      return match id.parse() {
+        Ok(id) => Self::Remove(id),
+        Err(_) => {
+          Self::Invalid("削除するお知らせの指定が不正です")
+        }
+      };
+    }
+    let Some(text) = non_empty(&form.announce_text) else {
+      return Self::Nop;
+    };
+    let config = crate::config::get();
+    let config = &config.service.announcements;
+    let starts_at = match non_empty(&form.announce_start) {
+      Some(start) => match config.parse_local(start) {
+        Some(start) => start,
+        None => {
+          return Self::Invalid("掲載開始日時の形式が不正です");
+        }
+      },
+      None => Utc::now(),
+    };
+    let ends_at = match non_empty(&form.announce_end) {
+      Some(end) => match config.parse_local(end) {
+        Some(end) if starts_at < end => Some(end),
+        Some(_) => {
+          return Self::Invalid(
+            "掲載終了日時は掲載開始日時より後にしてください",
+          );
+        }
+        None => {
+          return Self::Invalid("掲載終了日時の形式が不正です");
+        }
+      },
+      None => None,
+    };
+    let priority = match non_empty(&form.announce_priority) {
+      Some(priority) => match priority.parse() {
+        Ok(priority) => priority,
+        Err(_) => {
+          return Self::Invalid("優先度は整数にしてください");
+        }
+      },
+      None => 0,
+    };
+    Self::Add {
+      text,
+      starts_at,
+      ends_at,
+      priority,
+    }
+  }
+}
+
+/// ヘッダメニューの項目の編集
+enum MenuEdit {
+  Upsert { id: Option<u64>, item: MenuItem },
+  Remove(u64),
+  Invalid(&'static str),
+  Nop,
+}
+impl From<&MaintePageForm> for MenuEdit {
+  fn from(form: &MaintePageForm) -> Self {
+    let non_empty = |field: &Option<String>| {
+      field
+        .as_deref()
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(str::to_string)
+    };
+    let id = |field: &Option<String>| match non_empty(field) {
+      Some(id) => id.parse().map(Some).map_err(|_| ()),
+      None => Ok(None),
+    };
+    if let Some(remove) = non_empty(&form.menu_remove) {
+      return match remove.parse() {
+        Ok(remove) => Self::Remove(remove),
+        Err(_) => Self::Invalid("削除する項目の指定が不正です"),
+      };
+    }
+    let Some(label) = non_empty(&form.menu_label) else {
+      return Self::Nop;
+    };
+    let Some(kind) = non_empty(&form.menu_kind)
+      .and_then(|kind| MenuKind::from_name(&kind))
+    else {
+      return Self::Invalid("メニューの指定が不正です");
+    };
+    let (Ok(edit), Ok(parent)) =
+      (id(&form.menu_id), id(&form.menu_parent))
+    else {
+      return Self::Invalid("項目のIDは整数にしてください");
+    };
+    let order = match non_empty(&form.menu_order) {
+      Some(order) => match order.parse() {
+        Ok(order) => order,
+        Err(_) => {
+          return Self::Invalid("並び順は整数にしてください");
+        }
+      },
+      None => 0,
+    };
+    Self::Upsert {
+      id: edit,
+      item: MenuItem {
+        id: 0,
+        kind,
+        parent,
+        order,
+        label,
+        url: non_empty(&form.menu_url).unwrap_or_default(),
+        icon: non_empty(&form.menu_icon),
+        external: form.menu_external.is_some(),
+      },
+    }
+  }
+}
+
 async fn mainte_page_main(
   Form(mainte): Form<MaintePageForm>,
-) -> Result<impl IntoResponse, impl IntoResponse> {
+) -> Result<impl IntoResponse, AppError> {
   let ch_ud_mode = ChangeUserDataMode::from(&mainte);
-  let user_data = match usersys::UserData::<()>::load(
+  let announcement_edit = AnnouncementEdit::from(&mainte);
+  let menu_edit = MenuEdit::from(&mainte);
+  let user_data = usersys::UserData::<()>::load(
     &mainte.admin_name,
     &mainte.admin_password,
     match ch_ud_mode {
@@ -188,24 +355,11 @@ async fn mainte_page_main(
       }
       _ => None,
     },
-    &crate::CONFIG.maintenance_page.usersys_config,
+    &crate::config::get().maintenance_page.usersys_config,
     || Ok(()),
-  ) {
-    Ok(ud) => ud,
-    Err(e) => {
-      return Err(crate::bsod::bsod(
-        StatusCode::BAD_REQUEST,
-        None,
-        None,
-      ));
-    }
-  };
+  )?;
   let Some(mut user_data) = user_data else {
-    return Err(crate::bsod::bsod(
-      StatusCode::FORBIDDEN,
-      None,
-      None,
-    ));
+    return Err(AppError::AuthenticationFailed);
   };
 
   let mut output = String::new();
@@ -213,9 +367,10 @@ async fn mainte_page_main(
     &mut output,
     &mut user_data,
     ch_ud_mode,
+    announcement_edit,
+    menu_edit,
     &mainte,
-  )
-  .unwrap();
+  )?;
 
   Ok(Html(output))
 }
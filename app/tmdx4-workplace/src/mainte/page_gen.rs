@@ -1,90 +1,279 @@
-use std::borrow::Cow;
-use super::MAINTE_CSS;
+use std::{borrow::Cow, path::Path};
 
-use crate::usersys::{self, UserData};
+use askama::Template;
+use chrono::Utc;
+
+use crate::{
+  error::AppError,
+  html::Escaped,
+  service::{announcement::announcements, menu::menus},
+  usersys::{UserData, UserIdent},
+};
+
+#[derive(Template)]
+#[template(path = "mainte.html")]
+struct MaintePage<'a> {
+  mainte_css: String,
+  username: Escaped<&'a str>,
+  password: Escaped<&'a str>,
+  ident: Escaped<&'a UserIdent>,
+  messages: Vec<Escaped<Cow<'static, str>>>,
+  announcements: Vec<AnnouncementRow>,
+  menu_items: Vec<MenuItemRow>,
+}
+
+/// ヘッダメニューの項目の一覧の行
+struct MenuItemRow {
+  id: u64,
+  kind: &'static str,
+  parent: String,
+  order: i32,
+  label: Escaped<String>,
+  url: Escaped<String>,
+  icon: Escaped<String>,
+  external: bool,
+}
+
+/// お知らせの一覧の行
+struct AnnouncementRow {
+  id: u64,
+  text: Escaped<String>,
+  period: String,
+  priority: i32,
+  active: bool,
+}
 
 pub(super) fn page_gen(
   write: &mut impl std::fmt::Write,
   user_data: &mut crate::usersys::UserData<()>,
   ch_ud_mode: super::ChangeUserDataMode,
+  announcement_edit: super::AnnouncementEdit,
+  menu_edit: super::MenuEdit,
   form: &super::MaintePageForm,
-) -> Result<(), Box<dyn std::error::Error>> {
-  write.write_fmt(format_args!("\
-      <!doctype html>
-      <html lang='ja'>
-        <head>
-          <meta charset='utf-8'>
-          <title>メンテナンスページ</title>
-          <style>{MAINTE_CSS}</style>
-        </head>
-        <body>
-          <form action='' method='POST' id='trans-ownpage'>
-            <input type='hidden' name='admin-name' value='{username}'>
-            <input type='hidden' name='admin-password' value='{password}'>
-          </form>
-          <header>
-            <div class='title'><h1>メンテナンスページ</h1></div>
-            <div class='tail'></div>
-          </header>
-          <main>
-            <table>
-              <tr>
-                <th>ユーザ設定</th>
-                <th></th>
-              </tr>
-              <tr>
-                <td>ユーザ識別子</td>
-                <td>{ident}</td>
-              </tr>
-              <tr>
-                <td><label for='new-username'>新しいユーザの名前</label></td>
-                <td><input type='text' name='new-username' form='trans-ownpage'></td>
-              </tr>
-              <tr>
-                <td><label for='new-password'>新しいパスワード</label></td>
-                <td><input type='password' name='new-password' form='trans-ownpage'></td>
-              </tr>
-              <tr>
-                <td><label for='new-password-verify'>新しいパスワード(確認)</label></td>
-                <td><input type='password' name='new-password-verify' form='trans-ownpage'></td>
-              </tr>
-              <tr>
-                <td colspan='2'><input type='submit' name='submit' value='送信' form='trans-ownpage'></td>
-              </tr>
-              {change_pswd_msg_head}{change_pswd_msg}{change_pswd_msg_tail}
-            </table>
-          </main>
-        </body>
-      </html>
-    ",
-    change_pswd_msg_head = match ch_ud_mode {
-      super::ChangeUserDataMode::Nop => "", 
-      _ => "<tr><td colspan='2'>"
-    },
-    change_pswd_msg_tail = match ch_ud_mode {
-      super::ChangeUserDataMode::Nop => "", 
-      _ => "</td></tr>"
-    },
-    change_pswd_msg = match ch_ud_mode{
-      super::ChangeUserDataMode::NewUser { new_username, new_password } => {
-        UserData::new(new_username, new_password, (), &crate::CONFIG.maintenance_page.usersys_config).unwrap().save(&crate::CONFIG.maintenance_page.usersys_config).unwrap();
-        Cow::from("新しいユーザの登録")
-      },
-      super::ChangeUserDataMode::PswdChange { new_password: _ } => {
-        Cow::from("パスワードの変更")
-      },
-      super::ChangeUserDataMode::PswdIsTooShort => Cow::from(format!("パスワードの長さは{}以上にしてください", crate::CONFIG.maintenance_page.pswd_len_min)),
-      super::ChangeUserDataMode::PswdInvalid => Cow::from("新旧のパスワードが一致しません"),
-      super::ChangeUserDataMode::PswdEmptyNotAllow => Cow::from("ユーザ登録時にはパスワードを入力してください"),
-      super::ChangeUserDataMode::UserNameDuplicate => Cow::from("ユーザ名が重複しています"),
-      super::ChangeUserDataMode::Nop => Cow::from(""),
-    },
-      username = form.admin_name.as_str(),
-      password = match ch_ud_mode {
-        super::ChangeUserDataMode::PswdChange { new_password } => new_password, 
-        _ => form.admin_password.as_str(), 
-      },
-      ident = user_data.ident(),
-    ))?;
+) -> Result<(), AppError> {
+  let root_config = crate::config::get();
+  let config = &root_config.maintenance_page;
+  let mut messages = Vec::new();
+  messages.extend(match ch_ud_mode {
+    super::ChangeUserDataMode::NewUser {
+      new_username,
+      new_password,
+    } => {
+      UserData::new(
+        new_username,
+        new_password,
+        (),
+        &config.usersys_config,
+      )?
+      .save(&config.usersys_config)?;
+      Some(Cow::from("新しいユーザの登録"))
+    }
+    super::ChangeUserDataMode::PswdChange {
+      new_password: _,
+    } => Some(Cow::from("パスワードの変更")),
+    super::ChangeUserDataMode::PswdIsTooShort => {
+      Some(Cow::from(format!(
+        "パスワードの長さは{}以上にしてください",
+        config.pswd_len_min
+      )))
+    }
+    super::ChangeUserDataMode::PswdInvalid => {
+      Some(Cow::from("新旧のパスワードが一致しません"))
+    }
+    super::ChangeUserDataMode::PswdEmptyNotAllow => Some(
+      Cow::from("ユーザ登録時にはパスワードを入力してください"),
+    ),
+    super::ChangeUserDataMode::UserNameDuplicate => {
+      Some(Cow::from("ユーザ名が重複しています"))
+    }
+    super::ChangeUserDataMode::Nop => None,
+  });
+
+  // 共有しているデータのロックは編集する時だけ取り、ページの生成前に放す
+  let announce_config = &root_config.service.announcements;
+  messages.extend(match announcement_edit {
+    super::AnnouncementEdit::Add {
+      text,
+      starts_at,
+      ends_at,
+      priority,
+    } => {
+      let mut announcements = announcements().write();
+      announcements.post(
+        text.into(),
+        starts_at,
+        ends_at,
+        priority,
+      );
+      announcements.save(Path::new(&announce_config.file))?;
+      crate::main_page::bump_content_version();
+      Some(Cow::from("お知らせの追加"))
+    }
+    super::AnnouncementEdit::Remove(id) => {
+      let mut announcements = announcements().write();
+      match announcements.remove(id) {
+        Some(_) => {
+          announcements
+            .save(Path::new(&announce_config.file))?;
+          crate::main_page::bump_content_version();
+          Some(Cow::from("お知らせの削除"))
+        }
+        None => {
+          Some(Cow::from("削除するお知らせが見つかりません"))
+        }
+      }
+    }
+    super::AnnouncementEdit::Invalid(message) => {
+      Some(Cow::from(message))
+    }
+    super::AnnouncementEdit::Nop => None,
+  });
+
+  let menus_path = &root_config.service.menus.file;
+  messages.extend(match menu_edit {
+    super::MenuEdit::Upsert { id, item } => {
+      let mut menus = menus().write();
+      match menus.upsert(id, item) {
+        Ok(_) => {
+          menus.save(Path::new(menus_path))?;
+          crate::main_page::bump_content_version();
+          Some(Cow::from(match id {
+            Some(_) => "メニューの項目の変更",
+            None => "メニューの項目の追加",
+          }))
+        }
+        Err(message) => Some(Cow::from(message)),
+      }
+    }
+    super::MenuEdit::Remove(id) => {
+      let mut menus = menus().write();
+      match menus.remove(id) {
+        Some(_) => {
+          menus.save(Path::new(menus_path))?;
+          crate::main_page::bump_content_version();
+          Some(Cow::from("メニューの項目の削除"))
+        }
+        None => Some(Cow::from("削除する項目が見つかりません")),
+      }
+    }
+    super::MenuEdit::Invalid(message) => {
+      Some(Cow::from(message))
+    }
+    super::MenuEdit::Nop => None,
+  });
+  let menu_items = menus()
+    .read()
+    .all()
+    .into_iter()
+    .map(|item| MenuItemRow {
+      id: item.id,
+      kind: item.kind.name(),
+      parent: item
+        .parent
+        .map(|p| p.to_string())
+        .unwrap_or_default(),
+      order: item.order,
+      label: Escaped(item.label.clone()),
+      url: Escaped(item.url.clone()),
+      icon: Escaped(item.icon.clone().unwrap_or_default()),
+      external: item.external,
+    })
+    .collect();
+
+  let now = Utc::now();
+  let announcements = announcements()
+    .read()
+    .all()
+    .into_iter()
+    .map(|a| AnnouncementRow {
+      id: a.id,
+      text: Escaped(a.text.clone()),
+      period: format!(
+        "{} 〜 {}",
+        announce_config.format_local(&a.starts_at),
+        a.ends_at
+          .map(|end| announce_config.format_local(&end))
+          .unwrap_or_default()
+      ),
+      priority: a.priority,
+      active: a.is_active(now),
+    })
+    .collect();
+  MaintePage {
+    mainte_css: crate::stylesheet::href(
+      crate::stylesheet::Stylesheet::Mainte,
+    ),
+    username: Escaped(form.admin_name.as_str()),
+    password: Escaped(match ch_ud_mode {
+      super::ChangeUserDataMode::PswdChange { new_password } => {
+        new_password
+      }
+      _ => form.admin_password.as_str(),
+    }),
+    ident: Escaped(user_data.ident()),
+    messages: messages.into_iter().map(Escaped).collect(),
+    announcements,
+    menu_items,
+  }
+  .render_into(write)?;
   Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn render(username: &str, password: &str) -> String {
+    let ident = UserIdent::generate("Admin01").unwrap();
+    MaintePage {
+      mainte_css: String::new(),
+      username: Escaped(username),
+      password: Escaped(password),
+      ident: Escaped(&ident),
+      messages: vec![Escaped(Cow::from("<b>message</b>"))],
+      announcements: vec![AnnouncementRow {
+        id: 1,
+        text: Escaped("<i>announcement</i>".into()),
+        period: String::new(),
+        priority: 0,
+        active: true,
+      }],
+      menu_items: vec![MenuItemRow {
+        id: 1,
+        kind: "favorites",
+        parent: String::new(),
+        order: 0,
+        label: Escaped("<u>label</u>".into()),
+        url: Escaped("https://example.com/?a=1&b='2'".into()),
+        icon: Escaped(String::new()),
+        external: true,
+      }],
+    }
+    .render()
+    .unwrap()
+  }
+
+  #[test]
+  fn hostile_username_cannot_leave_the_attribute() {
+    let html = render("x' onfocus='alert(1)' autofocus='", "p");
+    assert!(html.contains(
+      "value='x&#39; onfocus=&#39;alert(1)&#39; autofocus=&#39;'"
+    ));
+  }
+
+  #[test]
+  fn hostile_password_cannot_inject_elements() {
+    let html = render("u", "'><script>alert(1)</script>");
+    assert!(!html.contains("<script>"));
+    assert!(html.contains("&#39;&gt;&lt;script&gt;"));
+  }
+
+  #[test]
+  fn messages_are_escaped() {
+    let html = render("u", "p");
+    assert!(html.contains("&lt;b&gt;message&lt;/b&gt;"));
+    assert!(html.contains("&lt;i&gt;announcement&lt;/i&gt;"));
+    assert!(html.contains("&lt;u&gt;label&lt;/u&gt;"));
+    assert!(html.contains("?a=1&amp;b=&#39;2&#39;"));
+  }
+}
@@ -0,0 +1,386 @@
+//! 表示設定の保存の実装
+//!
+//! クエリで指定された表示設定([`MainArgs`])を署名付きのクッキーに保存し、
+//! 以後のリクエストではクッキーの設定にクエリの指定を重ねて使う。
+//! 署名の鍵は`secret_file`に保存し、無ければ起動時に生成する
+
+use std::{
+  io::{ErrorKind, Write},
+  path::Path,
+  sync::OnceLock,
+};
+
+use axum::{
+  extract::{ConnectInfo, FromRequestParts, Query},
+  http::{
+    HeaderMap, HeaderValue, StatusCode, header, request::Parts,
+  },
+  response::AppendHeaders,
+};
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Sha3_256};
+
+use crate::{
+  bsod::Bsod,
+  listener::PeerAddr,
+  main_page::{
+    IsSelected, MainArgs, ViewMode, desktop::WindowId,
+  },
+};
+
+/// 表示設定のクッキーについてのコンフィグ
+#[derive(Deserialize, Serialize, Clone)]
+pub struct PreferencesConfig {
+  /// クッキーの名前
+  pub cookie_name: String,
+
+  /// クッキーの有効期間(秒)
+  pub max_age_secs: u64,
+
+  /// 署名の鍵を保存するファイル(再起動するまで変わらない)
+  pub secret_file: String,
+}
+impl Default for PreferencesConfig {
+  fn default() -> Self {
+    Self {
+      cookie_name: "tmdx4_prefs".into(),
+      max_age_secs: 365 * 24 * 60 * 60,
+      secret_file: "cookie-secret.bin".into(),
+    }
+  }
+}
+impl PreferencesConfig {
+  /// コンフィグの内容を検証し、見つかった問題を`problems`に追加する
+  pub fn validate(&self, problems: &mut Vec<String>) {
+    if self.cookie_name.is_empty()
+      || !self.cookie_name.bytes().all(|b| {
+        b.is_ascii_alphanumeric() || matches!(b, b'_' | b'-')
+      })
+    {
+      problems.push(
+        "preferences.cookie_name: [A-Za-z0-9_-]のみを使ってください".into(),
+      );
+    }
+    if self.max_age_secs == 0 {
+      problems.push(
+        "preferences.max_age_secs: 1以上にしてください".into(),
+      );
+    }
+  }
+}
+
+/// 署名の鍵
+static SECRET: OnceLock<[u8; 32]> = OnceLock::new();
+
+/// 署名の鍵を読み込む。ファイルが無ければ生成して保存する
+pub fn init(
+  config: &PreferencesConfig,
+) -> Result<(), Box<dyn std::error::Error>> {
+  let path = Path::new(&config.secret_file);
+  let secret = match std::fs::read(path) {
+    Ok(secret) => secret.try_into().map_err(|_| {
+      format!(
+        "{}: 署名の鍵が32バイトではありません",
+        path.display()
+      )
+    })?,
+    Err(e) if e.kind() == ErrorKind::NotFound => {
+      let secret = rand::random::<[u8; 32]>();
+      let mut file = std::fs::File::options();
+      file.write(true).create_new(true);
+      #[cfg(unix)]
+      std::os::unix::fs::OpenOptionsExt::mode(&mut file, 0o600);
+      file.open(path)?.write_all(&secret)?;
+      log::info!(
+        "generated a new cookie secret {}",
+        path.display()
+      );
+      secret
+    }
+    Err(e) => return Err(e.into()),
+  };
+  let _ = SECRET.set(secret);
+  Ok(())
+}
+
+fn sign(payload: &str) -> Option<[u8; 32]> {
+  let mut hasher = Sha3_256::new();
+  hasher.update(SECRET.get()?);
+  hasher.update(payload);
+  Some(hasher.finalize().into())
+}
+
+/// `<JSONの16進>.<署名の16進>`の形にする
+fn encode(args: &MainArgs) -> Option<String> {
+  let payload = hex::encode(serde_json::to_vec(args).ok()?);
+  let signature = hex::encode(sign(&payload)?);
+  Some(format!("{payload}.{signature}"))
+}
+
+/// 署名を確かめて取り出す(改竄されたもの・古い形式のものはNone)
+fn decode(value: &str) -> Option<MainArgs> {
+  let (payload, signature) = value.split_once('.')?;
+  let signature = hex::decode(signature).ok()?;
+  let expected = sign(payload)?;
+  // 一致するまでの時間から署名を推測されないよう全体を比べる
+  if signature.len() != expected.len()
+    || signature
+      .iter()
+      .zip(expected)
+      .fold(0, |diff, (a, b)| diff | (a ^ b))
+      != 0
+  {
+    return None;
+  }
+  serde_json::from_slice(&hex::decode(payload).ok()?).ok()
+}
+
+fn cookie<'a>(
+  headers: &'a HeaderMap,
+  name: &str,
+) -> Option<&'a str> {
+  headers
+    .get_all(header::COOKIE)
+    .iter()
+    .filter_map(|v| v.to_str().ok())
+    .flat_map(|v| v.split(';'))
+    .filter_map(|pair| pair.trim().split_once('='))
+    .find(|(key, _)| *key == name)
+    .map(|(_, value)| value)
+}
+
+/// クエリでの表示設定の指定
+#[derive(Deserialize)]
+struct PrefsQuery {
+  /// 選ばれたテーマのID(読み込んでいないものは無視する)
+  #[serde(alias = "view-mode")]
+  view_mode: Option<String>,
+  maximize: Option<IsSelected>,
+  minimize: Option<IsSelected>,
+  closed: Option<IsSelected>,
+  noheader: Option<IsSelected>,
+  notaskbar: Option<IsSelected>,
+  invframe: Option<IsSelected>,
+  noframe: Option<IsSelected>,
+  toggleframe: Option<IsSelected>,
+  /// 開く(元に戻して手前に出す)ウィンドウ
+  open: Option<WindowId>,
+  /// 最小化するウィンドウ
+  hide: Option<WindowId>,
+  /// 閉じるウィンドウ
+  close: Option<WindowId>,
+}
+impl PrefsQuery {
+  /// 保存されている設定にクエリの指定を重ねる
+  /// `view-mode`を含むものは表示設定のフォームの送信なので、
+  /// 送られなかったチェックボックスは外されたものとする
+  fn apply(self, saved: &MainArgs) -> MainArgs {
+    let submitted = self.view_mode.is_some();
+    let checkbox = |query: Option<IsSelected>,
+                    saved: IsSelected| {
+      query.unwrap_or(if submitted {
+        IsSelected::default()
+      } else {
+        saved
+      })
+    };
+    let mut windows = saved.windows.clone();
+    if let Some(id) = self.close {
+      windows.close(id);
+    }
+    if let Some(id) = self.hide {
+      windows.minimize(id);
+    }
+    if let Some(id) = self.open {
+      windows.open(id);
+    }
+    MainArgs {
+      view_mode: self
+        .view_mode
+        .as_deref()
+        .and_then(ViewMode::selected)
+        .unwrap_or_else(|| saved.view_mode.clone()),
+      maximize: checkbox(self.maximize, saved.maximize),
+      minimize: checkbox(self.minimize, saved.minimize),
+      closed: checkbox(self.closed, saved.closed),
+      noheader: checkbox(self.noheader, saved.noheader),
+      notaskbar: checkbox(self.notaskbar, saved.notaskbar),
+      invframe: checkbox(self.invframe, saved.invframe),
+      noframe: checkbox(self.noframe, saved.noframe),
+      toggleframe: checkbox(self.toggleframe, saved.toggleframe),
+      windows,
+    }
+  }
+}
+
+/// クッキーとクエリを合わせた表示設定
+pub struct ViewPrefs {
+  pub args: MainArgs,
+  /// 表示するテーマのID(`Auto`を決めたもの)
+  pub theme: String,
+  /// 表示するページのパス(メニューで今のページを強調する)
+  pub path: String,
+  /// クッキーを更新するか
+  changed: bool,
+  /// HTTPSで受けたリクエストか(クッキーに`Secure`を付ける)
+  secure: bool,
+}
+impl ViewPrefs {
+  /// 設定が変わった時に付ける`Set-Cookie`
+  pub fn set_cookie(
+    &self,
+  ) -> AppendHeaders<Option<(header::HeaderName, HeaderValue)>>
+  {
+    let value = self
+      .changed
+      .then(|| encode(&self.args))
+      .flatten()
+      .and_then(|value| {
+        let config = crate::config::get();
+        let config = &config.preferences;
+        HeaderValue::try_from(format!(
+          "{}={value}; Path=/; Max-Age={}; HttpOnly; SameSite=Lax{}",
+          config.cookie_name,
+          config.max_age_secs,
+          if self.secure { "; Secure" } else { "" }
+        ))
+        .ok()
+      });
+    AppendHeaders(value.map(|value| (header::SET_COOKIE, value)))
+  }
+}
+impl<S: Send + Sync> FromRequestParts<S> for ViewPrefs {
+  type Rejection = Bsod;
+
+  async fn from_request_parts(
+    parts: &mut Parts,
+    state: &S,
+  ) -> Result<Self, Self::Rejection> {
+    let Query(query) =
+      Query::<PrefsQuery>::from_request_parts(parts, state)
+        .await
+        .map_err(|e| {
+          Bsod::new(StatusCode::BAD_REQUEST).text(e.body_text())
+        })?;
+    let config = crate::config::get();
+    let saved =
+      cookie(&parts.headers, &config.preferences.cookie_name)
+        .and_then(decode)
+        .unwrap_or_default();
+    let args = query.apply(&saved);
+    let secure = matches!(
+      parts.extensions.get::<ConnectInfo<PeerAddr>>(),
+      Some(ConnectInfo(PeerAddr::Tls(_)))
+    );
+    Ok(Self {
+      changed: args != saved,
+      theme: args.view_mode.resolve(&parts.headers),
+      path: parts.uri.path().into(),
+      args,
+      secure,
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn query(query: &str) -> PrefsQuery {
+    let uri = format!("/?{query}").parse().unwrap();
+    Query::try_from_uri(&uri).unwrap().0
+  }
+
+  #[test]
+  fn form_submission_replaces_the_saved_checkboxes() {
+    let saved = query("view-mode=night&maximize=on&noheader=on")
+      .apply(&MainArgs::default());
+    let args = query("view-mode=night&noframe=on").apply(&saved);
+    assert_eq!(args.view_mode, ViewMode::from("night"));
+    assert_eq!(args.maximize, IsSelected::default());
+    assert_eq!(args.noheader, IsSelected::default());
+    assert_ne!(args.noframe, IsSelected::default());
+  }
+
+  #[test]
+  fn links_without_preferences_keep_the_saved_ones() {
+    let saved = query("view-mode=night&maximize=on")
+      .apply(&MainArgs::default());
+    assert_eq!(query("search-string=abc").apply(&saved), saved);
+    let args = query("noheader=on").apply(&saved);
+    assert_eq!(args.view_mode, ViewMode::from("night"));
+    assert_eq!(args.maximize, saved.maximize);
+    assert_ne!(args.noheader, IsSelected::default());
+  }
+
+  #[test]
+  fn unknown_view_modes_are_ignored() {
+    let saved = query("view-mode=night&maximize=on")
+      .apply(&MainArgs::default());
+    let args = query("view-mode=no-such-theme").apply(&saved);
+    assert_eq!(args.view_mode, ViewMode::from("night"));
+    // フォームの送信としては扱う
+    assert_eq!(args.maximize, IsSelected::default());
+    let args = query("view-mode=AUTO").apply(&saved);
+    assert_eq!(args.view_mode, ViewMode::Auto);
+  }
+
+  #[test]
+  fn links_open_and_close_windows() {
+    let saved = query("open=about").apply(&MainArgs::default());
+    let args = query("open=gallery").apply(&saved);
+    assert_eq!(args.windows.to_string(), "about,gallery");
+    let args = query("view-mode=night").apply(&args);
+    assert_eq!(args.windows.to_string(), "about,gallery");
+    let args = query("close=gallery&hide=about").apply(&args);
+    assert_eq!(args.windows.to_string(), "~about");
+    let args = query("open=about").apply(&args);
+    assert_eq!(args.windows.to_string(), "about");
+    assert!(
+      Query::<PrefsQuery>::try_from_uri(
+        &"/?open=desktop".parse().unwrap()
+      )
+      .is_err()
+    );
+  }
+
+  #[test]
+  fn the_main_window_can_be_minimized_and_closed() {
+    let saved = query("minimize=on").apply(&MainArgs::default());
+    assert!(saved.minimize.is_selected());
+    // 表示設定のフォームは隠したチェックボックスで状態を送る
+    let args = query("view-mode=auto&minimize=on&closed=on")
+      .apply(&saved);
+    assert!(
+      args.minimize.is_selected() && args.closed.is_selected()
+    );
+    let args = query("closed=off&minimize=off").apply(&args);
+    assert_eq!(args, MainArgs::default());
+  }
+
+  #[test]
+  fn tampered_cookies_are_ignored() {
+    let _ = SECRET.set([7; 32]);
+    let args =
+      query("view-mode=night").apply(&MainArgs::default());
+    let value = encode(&args).unwrap();
+    assert_eq!(decode(&value), Some(args));
+
+    let (payload, signature) = value.split_once('.').unwrap();
+    let forged = hex::encode(br#"{"view_mode":"DayTime"}"#);
+    assert_eq!(decode(&format!("{forged}.{signature}")), None);
+    assert_eq!(decode(&format!("{payload}.00")), None);
+    assert_eq!(decode(payload), None);
+  }
+
+  #[test]
+  fn cookies_are_found_among_others() {
+    let mut headers = HeaderMap::new();
+    headers.insert(
+      header::COOKIE,
+      HeaderValue::from_static("a=1; tmdx4_prefs=x.y; b=2"),
+    );
+    assert_eq!(cookie(&headers, "tmdx4_prefs"), Some("x.y"));
+    assert_eq!(cookie(&headers, "c"), None);
+  }
+}
@@ -0,0 +1,70 @@
+//! リクエストIDの実装
+//!
+//! リクエストごとにIDを決め(妥当な`X-Request-Id`が付いていればそれを使う)、
+//! 処理中に出したログ・アクセスログ・エラーページに載せ、レスポンスヘッダでも返す
+
+use axum::{
+  extract::Request,
+  http::{HeaderName, HeaderValue},
+  middleware::Next,
+  response::Response,
+};
+
+pub static X_REQUEST_ID: HeaderName =
+  HeaderName::from_static("x-request-id");
+
+/// 受け付けるリクエストIDの最大長
+const MAX_LEN: usize = 64;
+
+tokio::task_local! {
+  static REQUEST_ID: String;
+}
+
+/// 処理中のリクエストのID(リクエストの外ではNone)
+pub fn current() -> Option<String> {
+  REQUEST_ID.try_with(|id| id.clone()).ok()
+}
+
+/// ログに混ぜても安全な形のIDか
+fn is_valid(id: &str) -> bool {
+  (1..=MAX_LEN).contains(&id.len())
+    && id.bytes().all(|b| {
+      b.is_ascii_alphanumeric()
+        || matches!(b, b'-' | b'_' | b'.')
+    })
+}
+
+/// リクエストIDを決め、処理中に参照できるようにしてレスポンスヘッダで返す
+pub async fn request_id(
+  mut req: Request,
+  next: Next,
+) -> Response {
+  let id = req
+    .headers()
+    .get(&X_REQUEST_ID)
+    .and_then(|v| v.to_str().ok())
+    .filter(|id| is_valid(id))
+    .map(str::to_string)
+    .unwrap_or_else(|| hex::encode(rand::random::<[u8; 8]>()));
+  let value = HeaderValue::try_from(id.as_str())
+    .expect("request IDs are visible ASCII");
+  req.headers_mut().insert(&X_REQUEST_ID, value.clone());
+  let mut response = REQUEST_ID.scope(id, next.run(req)).await;
+  response.headers_mut().insert(&X_REQUEST_ID, value);
+  response
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn incoming_ids_that_could_forge_log_lines_are_rejected() {
+    assert!(is_valid("0f3a9c1d-42.b_7"));
+    assert!(!is_valid(""));
+    assert!(!is_valid("abc def"));
+    assert!(!is_valid("abc\n[2026-01-01 ERROR] forged"));
+    assert!(!is_valid("<script>"));
+    assert!(!is_valid(&"a".repeat(MAX_LEN + 1)));
+  }
+}
@@ -0,0 +1,292 @@
+//! セキュリティ関連のレスポンスヘッダの実装
+//!
+//! CSP・X-Frame-Options・Referrer-Policy・X-Content-Type-Options・
+//! Permissions-Policyをパスの前方一致で選んだ設定に従って付ける。
+//! CSPの`{nonce}`はレスポンスごとに生成した値に置き換え、
+//! ページ生成側は[`nonce`]で同じ値を`<style>`に付ける
+
+use std::collections::BTreeMap;
+
+use axum::{
+  extract::Request,
+  http::{HeaderName, HeaderValue, StatusCode, header},
+  middleware::Next,
+  response::Response,
+};
+use serde::{Deserialize, Serialize};
+
+/// CSP中でレスポンスごとのnonceに置き換える文字列
+const NONCE_PLACEHOLDER: &str = "{nonce}";
+
+tokio::task_local! {
+  static CSP_NONCE: String;
+}
+
+/// 一つのパスに対して付けるヘッダの設定(Noneのものは付けない)
+#[derive(Deserialize, Serialize, Clone)]
+pub struct SecurityPolicy {
+  /// `Content-Security-Policy`(`{nonce}`はレスポンスごとの値に置き換える)
+  pub content_security_policy: Option<String>,
+
+  /// `X-Frame-Options`
+  pub frame_options: Option<String>,
+
+  /// `Referrer-Policy`
+  pub referrer_policy: Option<String>,
+
+  /// `X-Content-Type-Options: nosniff`を付けるか
+  pub nosniff: bool,
+
+  /// `Permissions-Policy`
+  pub permissions_policy: Option<String>,
+}
+impl Default for SecurityPolicy {
+  fn default() -> Self {
+    Self {
+      // インラインのstyle属性は多用しているので許可する
+      content_security_policy: Some(
+        "default-src 'self'; \
+          style-src 'self' 'nonce-{nonce}' https://fonts.googleapis.com; \
+          style-src-elem 'self' 'nonce-{nonce}' https://fonts.googleapis.com; \
+          style-src-attr 'unsafe-inline'; \
+          font-src 'self' https://fonts.gstatic.com; \
+          img-src 'self' data:; \
+          script-src 'none'; \
+          object-src 'none'; \
+          base-uri 'none'; \
+          form-action 'self'; \
+          frame-ancestors 'none'"
+          .into(),
+      ),
+      frame_options: Some("DENY".into()),
+      referrer_policy: Some("strict-origin-when-cross-origin".into()),
+      nosniff: true,
+      permissions_policy: Some(
+        "camera=(), microphone=(), geolocation=(), payment=(), usb=()"
+          .into(),
+      ),
+    }
+  }
+}
+impl SecurityPolicy {
+  fn headers(
+    &self,
+    nonce: &str,
+  ) -> impl Iterator<Item = (HeaderName, Option<HeaderValue>)>
+  {
+    [
+      (
+        header::CONTENT_SECURITY_POLICY,
+        self
+          .content_security_policy
+          .as_ref()
+          .map(|csp| csp.replace(NONCE_PLACEHOLDER, nonce)),
+      ),
+      (header::X_FRAME_OPTIONS, self.frame_options.clone()),
+      (header::REFERRER_POLICY, self.referrer_policy.clone()),
+      (
+        header::X_CONTENT_TYPE_OPTIONS,
+        self.nosniff.then(|| "nosniff".into()),
+      ),
+      (
+        HeaderName::from_static("permissions-policy"),
+        self.permissions_policy.clone(),
+      ),
+    ]
+    .into_iter()
+    .map(|(name, value)| {
+      (name, value.and_then(|v| HeaderValue::try_from(v).ok()))
+    })
+  }
+}
+
+/// セキュリティヘッダについてのコンフィグ
+#[derive(Deserialize, Serialize, Clone)]
+pub struct SecurityHeadersConfig {
+  /// どの`routes`にも一致しないパスに使う設定
+  pub default: SecurityPolicy,
+
+  /// パスの前方一致で使う設定(最も長く一致したものを使う)
+  pub routes: BTreeMap<String, SecurityPolicy>,
+}
+impl Default for SecurityHeadersConfig {
+  fn default() -> Self {
+    Self {
+      default: SecurityPolicy::default(),
+      routes: BTreeMap::from([(
+        "/mainte".into(),
+        SecurityPolicy {
+          referrer_policy: Some("no-referrer".into()),
+          ..SecurityPolicy::default()
+        },
+      )]),
+    }
+  }
+}
+impl SecurityHeadersConfig {
+  /// コンフィグの内容を検証し、見つかった問題を`problems`に追加する
+  pub fn validate(&self, problems: &mut Vec<String>) {
+    let policies = std::iter::once((
+      "security_headers.default".to_string(),
+      &self.default,
+    ))
+    .chain(self.routes.iter().map(|(prefix, policy)| {
+      (format!("security_headers.routes.{prefix}"), policy)
+    }));
+    for (key, policy) in policies {
+      let values = [
+        (
+          "content_security_policy",
+          &policy.content_security_policy,
+        ),
+        ("frame_options", &policy.frame_options),
+        ("referrer_policy", &policy.referrer_policy),
+        ("permissions_policy", &policy.permissions_policy),
+      ];
+      for (name, value) in values {
+        if let Some(value) = value
+          && HeaderValue::try_from(value.as_str()).is_err()
+        {
+          problems.push(format!(
+            "{key}.{name}: ヘッダの値に使えません"
+          ));
+        }
+      }
+    }
+    for prefix in self.routes.keys() {
+      if !prefix.starts_with('/') {
+        problems.push(format!(
+          "security_headers.routes.{prefix}: パスの接頭辞は'/'で始めてください"
+        ));
+      }
+    }
+  }
+
+  fn policy(&self, path: &str) -> &SecurityPolicy {
+    self
+      .routes
+      .iter()
+      .filter(|(prefix, _)| path.starts_with(prefix.as_str()))
+      .max_by_key(|(prefix, _)| prefix.len())
+      .map(|(_, policy)| policy)
+      .unwrap_or(&self.default)
+  }
+}
+
+/// 処理中のレスポンスのCSP nonce
+/// ミドルウェアの外から呼ばれた場合は空文字列を返す
+pub fn nonce() -> String {
+  CSP_NONCE
+    .try_with(|nonce| nonce.clone())
+    .unwrap_or_default()
+}
+
+/// レスポンスにセキュリティヘッダを付ける
+/// ハンドラが既に付けたヘッダはそのままにする
+pub async fn security_headers(
+  req: Request,
+  next: Next,
+) -> Response {
+  let nonce = hex::encode(rand::random::<[u8; 16]>());
+  let config = crate::config::get();
+  let policy = config.security_headers.policy(req.uri().path());
+  let mut response =
+    CSP_NONCE.scope(nonce.clone(), next.run(req)).await;
+  // 304ではキャッシュ済みのページのnonceと食い違うのでCSPを付けない
+  let not_modified =
+    response.status() == StatusCode::NOT_MODIFIED;
+  let headers = response.headers_mut();
+  for (name, value) in policy.headers(&nonce) {
+    if let Some(value) = value
+      && !headers.contains_key(&name)
+      && !(not_modified
+        && name == header::CONTENT_SECURITY_POLICY)
+    {
+      headers.insert(name, value);
+    }
+  }
+  response
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn headers(
+    policy: &SecurityPolicy,
+    nonce: &str,
+  ) -> BTreeMap<String, String> {
+    policy
+      .headers(nonce)
+      .filter_map(|(name, value)| {
+        Some((
+          name.to_string(),
+          value?.to_str().ok()?.to_string(),
+        ))
+      })
+      .collect()
+  }
+
+  #[test]
+  fn the_nonce_is_filled_into_the_csp() {
+    let headers = headers(&SecurityPolicy::default(), "abc123");
+    let csp = &headers["content-security-policy"];
+    assert!(csp.contains("style-src 'self' 'nonce-abc123'"));
+    assert!(!csp.contains(NONCE_PLACEHOLDER));
+    assert_eq!(headers["x-content-type-options"], "nosniff");
+    assert_eq!(headers["x-frame-options"], "DENY");
+  }
+
+  #[test]
+  fn unset_headers_are_omitted() {
+    let policy = SecurityPolicy {
+      content_security_policy: None,
+      frame_options: None,
+      referrer_policy: Some("no-referrer".into()),
+      nosniff: false,
+      permissions_policy: None,
+    };
+    assert_eq!(
+      headers(&policy, "x").into_iter().collect::<Vec<_>>(),
+      [("referrer-policy".into(), "no-referrer".into())]
+    );
+  }
+
+  #[test]
+  fn the_longest_route_is_used() {
+    let config = SecurityHeadersConfig::default();
+    let referrer = |path| {
+      config.policy(path).referrer_policy.as_deref().unwrap()
+    };
+    assert_eq!(referrer("/mainte/users"), "no-referrer");
+    assert_eq!(referrer("/"), "strict-origin-when-cross-origin");
+  }
+
+  #[test]
+  fn invalid_values_and_prefixes_are_reported() {
+    let mut config = SecurityHeadersConfig::default();
+    config.default.frame_options = Some("DENY\n".into());
+    config
+      .routes
+      .insert("mainte".into(), SecurityPolicy::default());
+    let mut problems = Vec::new();
+    config.validate(&mut problems);
+    assert_eq!(problems.len(), 2, "{problems:?}");
+    assert!(
+      problems[0]
+        .starts_with("security_headers.default.frame_options:")
+    );
+    assert!(
+      problems[1].starts_with("security_headers.routes.mainte:")
+    );
+    problems.clear();
+    SecurityHeadersConfig::default().validate(&mut problems);
+    assert!(problems.is_empty(), "{problems:?}");
+  }
+
+  #[test]
+  fn nonce_is_empty_outside_the_middleware() {
+    assert_eq!(nonce(), "");
+    assert_eq!(CSP_NONCE.sync_scope("n".into(), nonce), "n");
+  }
+}
@@ -0,0 +1,275 @@
+//! お知らせの実装
+//!
+//! お知らせは掲載の開始・終了日時と優先度を持ち、メンテナンスページで追加・削除する。
+//! 掲載中のものはメインページのマーキーに優先度の高い順で流し、掲載を始めたものは全てお知らせの一覧に載せる。
+//! 日時はサイトのタイムゾーン(`utc_offset`)での入力をUTCにして保存する
+
+use std::{cmp::Reverse, path::Path, sync::OnceLock};
+
+use chrono::{DateTime, FixedOffset, NaiveDateTime, Utc};
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+
+use crate::error::AppError;
+
+/// お知らせについてのコンフィグ
+#[derive(Deserialize, Serialize, Clone)]
+pub struct AnnouncementsConfig {
+  /// お知らせを保存するファイル
+  pub file: String,
+
+  /// 日時の入力と表示に使うタイムゾーン(UTCからのオフセット、`+09:00`等)
+  pub utc_offset: String,
+
+  /// マーキーに流す最大の件数
+  pub marquee_max: usize,
+}
+impl Default for AnnouncementsConfig {
+  fn default() -> Self {
+    Self {
+      file: "./announcements.bin".into(),
+      utc_offset: "+09:00".into(),
+      marquee_max: 5,
+    }
+  }
+}
+impl AnnouncementsConfig {
+  /// コンフィグの内容を検証し、見つかった問題を`problems`に追加する
+  pub fn validate(&self, problems: &mut Vec<String>) {
+    if self.utc_offset.parse::<FixedOffset>().is_err() {
+      problems.push(format!(
+        "service.announcements.utc_offset: {}は+09:00のようなオフセットではありません",
+        self.utc_offset
+      ));
+    }
+    if self.marquee_max == 0 {
+      problems.push(
+        "service.announcements.marquee_max: 1以上にしてください"
+          .into(),
+      );
+    }
+  }
+
+  pub fn offset(&self) -> FixedOffset {
+    self
+      .utc_offset
+      .parse()
+      .unwrap_or(FixedOffset::east_opt(0).unwrap())
+  }
+
+  /// `datetime-local`の入力(`2026-01-01T09:00`)をUTCにする
+  pub fn parse_local(
+    &self,
+    input: &str,
+  ) -> Option<DateTime<Utc>> {
+    let input = input.trim();
+    let local =
+      NaiveDateTime::parse_from_str(input, "%Y-%m-%dT%H:%M")
+        .or_else(|_| {
+          NaiveDateTime::parse_from_str(
+            input,
+            "%Y-%m-%dT%H:%M:%S",
+          )
+        })
+        .ok()?;
+    Some(
+      local.and_local_timezone(self.offset()).single()?.to_utc(),
+    )
+  }
+
+  /// 表示用の日時
+  pub fn format_local(&self, time: &DateTime<Utc>) -> String {
+    time
+      .with_timezone(&self.offset())
+      .format("%Y/%m/%d %H:%M")
+      .to_string()
+  }
+}
+
+/// お知らせ
+#[derive(
+  Serialize, Deserialize, Clone, Debug, PartialEq, Eq,
+)]
+pub struct Announcement {
+  pub id: u64,
+  pub text: String,
+  pub starts_at: DateTime<Utc>,
+  /// 掲載の終了日時(Noneなら削除するまで掲載する)
+  pub ends_at: Option<DateTime<Utc>>,
+  /// 大きいほど先に流す
+  pub priority: i32,
+}
+impl Announcement {
+  pub fn is_active(&self, now: DateTime<Utc>) -> bool {
+    self.starts_at <= now
+      && self.ends_at.is_none_or(|end| now < end)
+  }
+}
+
+/// お知らせの一覧
+#[derive(Serialize, Deserialize, Default)]
+pub struct AnnouncementService {
+  next_id: u64,
+  list: Vec<Announcement>,
+}
+impl AnnouncementService {
+  pub fn post(
+    &mut self,
+    text: String,
+    starts_at: DateTime<Utc>,
+    ends_at: Option<DateTime<Utc>>,
+    priority: i32,
+  ) -> &Announcement {
+    self.next_id += 1;
+    self.list.push(Announcement {
+      id: self.next_id,
+      text,
+      starts_at,
+      ends_at,
+      priority,
+    });
+    self.list.last().unwrap()
+  }
+
+  pub fn remove(&mut self, id: u64) -> Option<Announcement> {
+    let index = self.list.iter().position(|a| a.id == id)?;
+    Some(self.list.remove(index))
+  }
+
+  /// 掲載中のお知らせ(優先度の高い順、同じなら新しい順)
+  pub fn active(
+    &self,
+    now: DateTime<Utc>,
+  ) -> Vec<&Announcement> {
+    let mut active = self
+      .list
+      .iter()
+      .filter(|a| a.is_active(now))
+      .collect::<Vec<_>>();
+    active.sort_by(|a, b| {
+      b.priority
+        .cmp(&a.priority)
+        .then(b.starts_at.cmp(&a.starts_at))
+    });
+    active
+  }
+
+  /// 掲載を始めたお知らせ(新しい順)
+  pub fn archive(
+    &self,
+    now: DateTime<Utc>,
+  ) -> Vec<&Announcement> {
+    let mut archive = self
+      .list
+      .iter()
+      .filter(|a| a.starts_at <= now)
+      .collect::<Vec<_>>();
+    archive.sort_by_key(|a| Reverse(a.starts_at));
+    archive
+  }
+
+  /// 全てのお知らせ(掲載予定のものを含む、新しい順)
+  pub fn all(&self) -> Vec<&Announcement> {
+    let mut all = self.list.iter().collect::<Vec<_>>();
+    all.sort_by_key(|a| Reverse(a.starts_at));
+    all
+  }
+
+  /// 保存済みのお知らせを読み込む。無ければ空の一覧にする
+  pub fn load(path: &Path) -> Result<Self, AppError> {
+    match std::fs::File::open(path) {
+      Ok(fp) => {
+        Ok(rmp_serde::from_read(std::io::BufReader::new(fp))?)
+      }
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+        Ok(Self::default())
+      }
+      Err(e) => Err(AppError::Io(e)),
+    }
+  }
+
+  /// 書きかけのファイルが残らないよう一時ファイルに書いてから置き換える
+  pub fn save(&self, path: &Path) -> Result<(), AppError> {
+    let temp = path.with_extension("tmp");
+    rmp_serde::encode::write(
+      &mut std::io::BufWriter::new(std::fs::File::create(
+        &temp,
+      )?),
+      self,
+    )?;
+    std::fs::rename(temp, path)?;
+    Ok(())
+  }
+}
+
+/// サーバで共有するお知らせ
+static ANNOUNCEMENTS: OnceLock<RwLock<AnnouncementService>> =
+  OnceLock::new();
+
+/// 保存済みのお知らせを読み込んでサーバで共有する
+pub fn init(
+  config: &AnnouncementsConfig,
+) -> Result<(), Box<dyn std::error::Error>> {
+  let service =
+    AnnouncementService::load(Path::new(&config.file))?;
+  let _ = ANNOUNCEMENTS.set(RwLock::new(service));
+  Ok(())
+}
+
+/// サーバで共有しているお知らせ
+pub fn announcements() -> &'static RwLock<AnnouncementService> {
+  ANNOUNCEMENTS
+    .get()
+    .expect("announcement service is not initialized")
+}
+
+/// 共有しているお知らせを保存する
+pub fn persist(
+  config: &AnnouncementsConfig,
+) -> Result<(), Box<dyn std::error::Error>> {
+  if let Some(announcements) = ANNOUNCEMENTS.get() {
+    announcements.read().save(Path::new(&config.file))?;
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn at(hour: u32) -> DateTime<Utc> {
+    AnnouncementsConfig::default()
+      .parse_local(&format!("2026-01-01T{hour:02}:00"))
+      .unwrap()
+  }
+
+  #[test]
+  fn only_announcements_in_their_period_are_active() {
+    let mut service = AnnouncementService::default();
+    service.post("low".into(), at(1), None, 0);
+    service.post("high".into(), at(2), Some(at(4)), 10);
+    service.post("future".into(), at(9), None, 99);
+    let texts = |list: Vec<&Announcement>| {
+      list
+        .into_iter()
+        .map(|a| a.text.clone())
+        .collect::<Vec<_>>()
+    };
+    assert_eq!(texts(service.active(at(3))), ["high", "low"]);
+    assert_eq!(texts(service.active(at(4))), ["low"]);
+    assert_eq!(texts(service.archive(at(4))), ["high", "low"]);
+
+    let id = service.active(at(3))[0].id;
+    assert!(service.remove(id).is_some());
+    assert_eq!(texts(service.archive(at(4))), ["low"]);
+  }
+
+  #[test]
+  fn local_times_are_stored_as_utc() {
+    let config = AnnouncementsConfig::default();
+    let time = config.parse_local("2026-01-01T09:00").unwrap();
+    assert_eq!(time.to_rfc3339(), "2026-01-01T00:00:00+00:00");
+    assert_eq!(config.format_local(&time), "2026/01/01 09:00");
+    assert!(config.parse_local("tomorrow").is_none());
+  }
+}
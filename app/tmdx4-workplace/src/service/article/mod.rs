@@ -1,14 +1,32 @@
 //! 記事管理システムの実装
 
-use hashbrown::HashMap;
+use hashbrown::{HashMap, HashSet};
 use serde::{Deserialize, Serialize};
-use std::{collections::VecDeque, sync::LazyLock};
+use std::{
+  collections::VecDeque,
+  path::{Path, PathBuf},
+  sync::OnceLock,
+};
+
+use crate::error::AppError;
 
 #[derive(Deserialize, Serialize)]
 pub struct ArticlesConfig {
   pub article_rootpath: String,
   pub articles_path: String,
 }
+impl ArticlesConfig {
+  /// 記事IDマスタのファイルパス
+  pub fn id_master_path(&self) -> PathBuf {
+    Path::new(&self.article_rootpath)
+      .join("article_id_master.bin")
+  }
+
+  /// 記事エントリを保存するディレクトリのパス
+  pub fn entries_path(&self) -> PathBuf {
+    Path::new(&self.article_rootpath).join(&self.articles_path)
+  }
+}
 impl Default for ArticlesConfig {
   fn default() -> Self {
     Self {
@@ -20,70 +38,152 @@ impl Default for ArticlesConfig {
 
 /// 記事のID
 #[derive(
-  Serialize, Deserialize, PartialEq, Eq, Hash, Clone, Copy,
+  Serialize,
+  Deserialize,
+  Debug,
+  PartialEq,
+  Eq,
+  PartialOrd,
+  Ord,
+  Hash,
+  Clone,
+  Copy,
 )]
 pub struct ArticleID(u64);
+impl std::fmt::Display for ArticleID {
+  fn fmt(
+    &self,
+    f: &mut std::fmt::Formatter<'_>,
+  ) -> std::fmt::Result {
+    self.0.fmt(f)
+  }
+}
+impl std::str::FromStr for ArticleID {
+  type Err = std::num::ParseIntError;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    s.trim().parse().map(Self)
+  }
+}
 
 /// 記事のIDのマスタ
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Default)]
 pub struct ArticleIDMaster(u64);
 impl ArticleIDMaster {
+  /// マスタを読み込む。無ければ初期状態で作成する
+  pub fn load(
+    config: &ArticlesConfig,
+  ) -> Result<Self, AppError> {
+    match std::fs::File::open(config.id_master_path()) {
+      Ok(fp) => {
+        Ok(rmp_serde::from_read(std::io::BufReader::new(fp))?)
+      }
+      Err(e) => match e.kind() {
+        std::io::ErrorKind::NotFound => {
+          let default = Self::default();
+          std::fs::create_dir_all(config.entries_path())?;
+          default.save(&config.id_master_path())?;
+          Ok(default)
+        }
+        _ => Err(AppError::Io(e)),
+      },
+    }
+  }
+
+  fn save(&self, path: &Path) -> Result<(), AppError> {
+    rmp_serde::encode::write(
+      &mut std::io::BufWriter::new(std::fs::File::create(path)?),
+      self,
+    )?;
+    Ok(())
+  }
+
+  /// ファイルの値の方が進んでいれば合わせる
+  /// (サーバの動作中にCLIが発行した分を再び発行しないようにする)
+  fn sync(&mut self, path: &Path) -> Result<(), AppError> {
+    match std::fs::File::open(path) {
+      Ok(fp) => {
+        let Self(issued) =
+          rmp_serde::from_read(std::io::BufReader::new(fp))?;
+        self.0 = self.0.max(issued);
+        Ok(())
+      }
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+        Ok(())
+      }
+      Err(e) => Err(AppError::Io(e)),
+    }
+  }
+
   pub fn issue(
     &mut self,
-  ) -> Result<ArticleID, Box<dyn std::error::Error>> {
+    path: &Path,
+  ) -> Result<ArticleID, AppError> {
+    self.sync(path)?;
     let r = ArticleID(self.0);
     self.0 = self.0.wrapping_add(1);
-    rmp_serde::encode::write(
-      &mut std::fs::File::open(
-        &crate::CONFIG.service.articles.article_rootpath,
-      )?,
-      &self,
-    )?;
+    self.save(path)?;
     Ok(r)
   }
 }
-impl Default for ArticleIDMaster {
-  fn default() -> Self {
-    Self(0u64)
-  }
-}
-static ARTICLE_ID: LazyLock<
-  parking_lot::Mutex<ArticleIDMaster>,
-> = LazyLock::new(|| {
-  let config = &crate::CONFIG.service.articles;
-  let article_rootpath =
-    config.article_rootpath.clone() + "/article_id_master.bin";
-  match std::fs::File::open(&article_rootpath) {
-    Ok(fp) => parking_lot::Mutex::new(
-      rmp_serde::from_read(std::io::BufReader::new(fp)).unwrap(),
-    ),
-    Err(e) => match e.kind() {
-      std::io::ErrorKind::NotFound => {
-        let default = ArticleIDMaster::default();
-        let articles_entries_path = article_rootpath.clone()
-          + config.articles_path.as_str();
-        if std::fs::exists(articles_entries_path.as_str())
-          .unwrap()
-        {
-          std::fs::create_dir_all(
-            article_rootpath.clone()
-              + config.articles_path.as_str(),
-          )
-          .unwrap();
-        }
-        rmp_serde::encode::write(
-          &mut std::io::BufWriter::new(
-            std::fs::File::create(&article_rootpath).unwrap(),
-          ),
-          &default,
-        )
-        .unwrap();
-        parking_lot::Mutex::new(default)
-      }
-      _ => panic!("{e}"),
-    },
+
+/// 読み込み元のパスと組にした記事IDのマスタ
+/// コンフィグの再読み込みでパスが変わったら読み込み直す
+static ARTICLE_ID: parking_lot::Mutex<
+  Option<(PathBuf, ArticleIDMaster)>,
+> = parking_lot::Mutex::new(None);
+
+/// 現在のコンフィグのマスタから記事IDを発行する
+fn issue_article_id() -> Result<ArticleID, AppError> {
+  let config = crate::config::get();
+  let path = config.service.articles.id_master_path();
+  let mut article_id = ARTICLE_ID.lock();
+  match &mut *article_id {
+    Some((loaded, master)) if *loaded == path => {
+      master.issue(&path)
+    }
+    slot => {
+      let mut master =
+        ArticleIDMaster::load(&config.service.articles)?;
+      let r = master.issue(&path);
+      *slot = Some((path, master));
+      r
+    }
+  }
+}
+
+/// サーバで共有する記事提供サービス
+static ARTICLES: OnceLock<parking_lot::RwLock<ArticleService>> =
+  OnceLock::new();
+
+/// 保存済みの記事を読み込んでサーバで共有する
+pub fn init(
+  config: &ArticlesConfig,
+) -> Result<(), Box<dyn std::error::Error>> {
+  let service = ArticleService::load(config)?;
+  let _ = ARTICLES.set(parking_lot::RwLock::new(service));
+  Ok(())
+}
+
+/// サーバで共有している記事提供サービス
+pub fn articles() -> &'static parking_lot::RwLock<ArticleService>
+{
+  ARTICLES.get().expect("article service is not initialized")
+}
+
+/// 共有している記事と記事IDのマスタを保存する
+pub fn persist(
+  config: &ArticlesConfig,
+) -> Result<(), Box<dyn std::error::Error>> {
+  if let Some((path, master)) = &mut *ARTICLE_ID.lock() {
+    master.sync(path)?;
+    master.save(path)?;
+  }
+  if let Some(articles) = ARTICLES.get() {
+    articles.write().save(config)?;
   }
-});
+  Ok(())
+}
 
 /// 記事
 #[derive(Serialize, Deserialize)]
@@ -92,12 +192,34 @@ pub struct ArticleData {
   title: String,
   body: String,
 }
+impl ArticleData {
+  pub fn id(&self) -> ArticleID {
+    self.id
+  }
+
+  pub fn title(&self) -> &str {
+    &self.title
+  }
+
+  pub fn body(&self) -> &str {
+    &self.body
+  }
+}
 
 /// 記事提供サービス
 pub struct ArticleService {
   table: HashMap<ArticleID, usize>,
   articles: Vec<Option<ArticleData>>,
   remove_queue: VecDeque<usize>,
+  /// 読み込み・保存の後に投稿された記事
+  posted: HashSet<ArticleID>,
+  /// 読み込み・保存の後に削除された記事
+  removed: HashSet<ArticleID>,
+}
+impl Default for ArticleService {
+  fn default() -> Self {
+    Self::new()
+  }
 }
 impl ArticleService {
   pub fn new() -> Self {
@@ -105,6 +227,8 @@ impl ArticleService {
       table: HashMap::new(),
       articles: Vec::new(),
       remove_queue: VecDeque::new(),
+      posted: HashSet::new(),
+      removed: HashSet::new(),
     }
   }
   pub fn post(
@@ -112,7 +236,7 @@ impl ArticleService {
     title: String,
     body: String,
   ) -> Result<&ArticleData, Box<dyn std::error::Error>> {
-    let aid = ARTICLE_ID.lock().issue()?;
+    let aid = issue_article_id()?;
     let index =
       if let Some(index) = self.remove_queue.pop_front() {
         self.articles[index] = Some(ArticleData {
@@ -133,6 +257,8 @@ impl ArticleService {
     if self.table.insert(aid, index).is_some() {
       panic!("Article ID is INVALID!")
     }
+    self.posted.insert(aid);
+    crate::main_page::bump_content_version();
     Ok(self.articles[index].as_ref().unwrap())
   }
   pub fn remove(
@@ -140,8 +266,11 @@ impl ArticleService {
     aid: &ArticleID,
   ) -> Option<ArticleData> {
     let index = self.table.remove(aid)?;
-    let art = self.articles.remove(index)?;
+    let art = self.articles[index].take()?;
     self.remove_queue.push_back(index);
+    self.posted.remove(aid);
+    self.removed.insert(*aid);
+    crate::main_page::bump_content_version();
     Some(art)
   }
   pub fn request(
@@ -156,4 +285,140 @@ impl ArticleService {
       .iter()
       .filter_map(|(_aid, index)| self.articles[*index].as_ref())
   }
+
+  /// 保存済みの記事を全て読み込む
+  pub fn load(
+    config: &ArticlesConfig,
+  ) -> Result<Self, Box<dyn std::error::Error>> {
+    let mut service = Self::new();
+    let dir = match std::fs::read_dir(config.entries_path()) {
+      Ok(dir) => dir,
+      Err(e) => match e.kind() {
+        std::io::ErrorKind::NotFound => return Ok(service),
+        _ => return Err(Box::from(e)),
+      },
+    };
+    for entry in dir {
+      let path = entry?.path();
+      if path.extension().is_none_or(|ext| ext != "bin") {
+        continue;
+      }
+      let article: ArticleData = rmp_serde::from_read(
+        std::io::BufReader::new(std::fs::File::open(&path)?),
+      )?;
+      let index = service.articles.len();
+      if service.table.insert(article.id, index).is_some() {
+        return Err(Box::from(format!(
+          "duplicated article id {} in {}",
+          article.id,
+          path.display()
+        )));
+      }
+      service.articles.push(Some(article));
+    }
+    Ok(service)
+  }
+
+  /// 読み込み・保存の後に投稿・削除した記事だけをファイルに反映する
+  /// 他のプロセス(CLIの`article import`等)が書いた記事のファイルには触れない
+  pub fn save(
+    &mut self,
+    config: &ArticlesConfig,
+  ) -> Result<(), Box<dyn std::error::Error>> {
+    let entries_path = config.entries_path();
+    std::fs::create_dir_all(&entries_path)?;
+    for aid in &self.posted {
+      let Some(article) = self.request(aid) else {
+        continue;
+      };
+      rmp_serde::encode::write(
+        &mut std::io::BufWriter::new(std::fs::File::create(
+          entries_path.join(format!("{aid}.bin")),
+        )?),
+        article,
+      )?;
+    }
+    for aid in &self.removed {
+      match std::fs::remove_file(
+        entries_path.join(format!("{aid}.bin")),
+      ) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+          return Err(Box::from(e));
+        }
+        _ => {}
+      }
+    }
+    self.posted.clear();
+    self.removed.clear();
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn test_config(name: &str) -> ArticlesConfig {
+    let root = std::env::temp_dir().join(format!(
+      "tmdx4-articles-{name}-{}",
+      std::process::id()
+    ));
+    let _ = std::fs::remove_dir_all(&root);
+    ArticlesConfig {
+      article_rootpath: root.to_string_lossy().into_owned(),
+      articles_path: "entries".into(),
+    }
+  }
+
+  fn article(id: u64) -> ArticleData {
+    ArticleData {
+      id: ArticleID(id),
+      title: format!("記事{id}"),
+      body: String::new(),
+    }
+  }
+
+  /// `post`はコンフィグのマスタを使うので、IDを決めて追加する
+  fn add(service: &mut ArticleService, id: u64) {
+    service.table.insert(ArticleID(id), service.articles.len());
+    service.articles.push(Some(article(id)));
+    service.posted.insert(ArticleID(id));
+  }
+
+  #[test]
+  fn saving_keeps_articles_written_by_others() {
+    let config = test_config("save");
+    let mut service = ArticleService::load(&config).unwrap();
+    add(&mut service, 0);
+    add(&mut service, 1);
+    service.save(&config).unwrap();
+
+    // サーバの動作中にCLIが取り込んだ記事
+    let mut cli = ArticleService::load(&config).unwrap();
+    add(&mut cli, 2);
+    cli.save(&config).unwrap();
+
+    assert!(service.remove(&ArticleID(0)).is_some());
+    assert!(service.request(&ArticleID(1)).is_some());
+    service.save(&config).unwrap();
+
+    let loaded = ArticleService::load(&config).unwrap();
+    let mut ids =
+      loaded.iter().map(|a| a.id().0).collect::<Vec<_>>();
+    ids.sort();
+    assert_eq!(ids, [1, 2]);
+    std::fs::remove_dir_all(&config.article_rootpath).unwrap();
+  }
+
+  #[test]
+  fn issued_ids_follow_the_file() {
+    let config = test_config("ids");
+    let path = config.id_master_path();
+    let mut server = ArticleIDMaster::load(&config).unwrap();
+    let mut cli = ArticleIDMaster::load(&config).unwrap();
+    assert_eq!(cli.issue(&path).unwrap(), ArticleID(0));
+    assert_eq!(cli.issue(&path).unwrap(), ArticleID(1));
+    assert_eq!(server.issue(&path).unwrap(), ArticleID(2));
+    std::fs::remove_dir_all(&config.article_rootpath).unwrap();
+  }
 }
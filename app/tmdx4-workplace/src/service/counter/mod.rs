@@ -0,0 +1,378 @@
+//! 訪問者カウンタの実装
+//!
+//! 累計と本日の訪問者数を数え、メインページにいにしえの回転式カウンタとして表示する。
+//! 同じ日の同じ訪問者は一度だけ数える。訪問者は接続元のIP(IPv6は/64単位)を日ごとの塩と合わせたハッシュで見分ける。
+//! 塩と訪問者のハッシュはメモリ上にだけ持ち、ファイルには書き出さない
+//! (再起動すると同じ日の訪問者をもう一度数えることがある)。
+//! 数はメモリ上で数え、定期的に`file`へ書き出す
+
+use std::{
+  convert::Infallible,
+  net::IpAddr,
+  path::{Path, PathBuf},
+  time::Duration,
+};
+
+use axum::{
+  extract::{ConnectInfo, FromRequestParts},
+  http::request::Parts,
+};
+use chrono::{FixedOffset, NaiveDate, Utc};
+use hashbrown::HashMap;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Sha3_256};
+
+use crate::listener::PeerAddr;
+
+/// 一日に見分ける訪問者の数の上限(超えた分の新しい訪問者は数えない)
+const MAX_SEEN: usize = 100_000;
+
+/// 訪問者カウンタについてのコンフィグ
+#[derive(Deserialize, Serialize, Clone)]
+pub struct CounterConfig {
+  /// 数を保存するファイル(再起動するまで変わらない)
+  pub file: String,
+
+  /// ファイルに書き出す間隔(秒)
+  pub flush_interval_secs: u64,
+
+  /// 日の区切りに使うタイムゾーン(UTCからのオフセット、`+09:00`等)
+  pub utc_offset: String,
+
+  /// カウンタの最小の桁数(足りない桁は0で埋める)
+  pub digits: usize,
+}
+impl Default for CounterConfig {
+  fn default() -> Self {
+    Self {
+      file: "./counter.bin".into(),
+      flush_interval_secs: 60,
+      utc_offset: "+09:00".into(),
+      digits: 6,
+    }
+  }
+}
+impl CounterConfig {
+  /// コンフィグの内容を検証し、見つかった問題を`problems`に追加する
+  pub fn validate(&self, problems: &mut Vec<String>) {
+    if self.flush_interval_secs == 0 {
+      problems.push(
+        "service.counter.flush_interval_secs: 1以上にしてください"
+          .into(),
+      );
+    }
+    if self.utc_offset.parse::<FixedOffset>().is_err() {
+      problems.push(format!(
+        "service.counter.utc_offset: {}は+09:00のようなオフセットではありません",
+        self.utc_offset
+      ));
+    }
+    if !(1..=20).contains(&self.digits) {
+      problems.push(
+        "service.counter.digits: 1〜20にしてください".into(),
+      );
+    }
+  }
+
+  /// このタイムゾーンでの今日
+  fn today(&self) -> NaiveDate {
+    let offset = self
+      .utc_offset
+      .parse::<FixedOffset>()
+      .unwrap_or(FixedOffset::east_opt(0).unwrap());
+    Utc::now().with_timezone(&offset).date_naive()
+  }
+}
+
+/// 保存するカウンタの状態
+#[derive(Serialize, Deserialize)]
+struct CounterState {
+  total: u64,
+  date: NaiveDate,
+  daily: u64,
+  /// 本日の塩(保存しない)
+  #[serde(skip, default = "rand::random")]
+  salt: [u8; 32],
+  /// 本日の訪問者(IPと塩のハッシュ)と、その訪問者が何人目か(保存しない)
+  #[serde(skip)]
+  seen: HashMap<[u8; 16], u64>,
+}
+impl CounterState {
+  fn new(today: NaiveDate) -> Self {
+    Self {
+      total: 0,
+      date: today,
+      daily: 0,
+      salt: rand::random(),
+      seen: HashMap::new(),
+    }
+  }
+
+  /// 訪問を数える。本日既に来た訪問者なら数えずに前回の番号を返す
+  /// 接続元が分からない場合と、見分ける訪問者が上限に達した後の新しい訪問者は数えない
+  fn visit(
+    &mut self,
+    ip: Option<IpAddr>,
+    today: NaiveDate,
+  ) -> (Visit, bool) {
+    if self.date != today {
+      self.date = today;
+      self.daily = 0;
+      self.salt = rand::random();
+      self.seen.clear();
+    }
+    let mut counted = false;
+    let number = match ip.map(|ip| self.key(ip)) {
+      Some(key) if self.seen.contains_key(&key) => {
+        self.seen[&key]
+      }
+      Some(key) if self.seen.len() < MAX_SEEN => {
+        counted = true;
+        self.total += 1;
+        self.daily += 1;
+        self.seen.insert(key, self.total);
+        self.total
+      }
+      _ => self.total,
+    };
+    let visit = Visit {
+      number,
+      total: self.total,
+      daily: self.daily,
+    };
+    (visit, counted)
+  }
+
+  /// 訪問者を見分けるハッシュ
+  /// IPv6は一つのクライアントが/64の中でアドレスを変えられるので、/64単位で見分ける
+  fn key(&self, ip: IpAddr) -> [u8; 16] {
+    let mut hasher = Sha3_256::new();
+    hasher.update(self.salt);
+    match ip.to_canonical() {
+      IpAddr::V4(ip) => hasher.update(ip.octets()),
+      IpAddr::V6(ip) => hasher.update(&ip.octets()[..8]),
+    }
+    hasher.finalize()[..16]
+      .try_into()
+      .expect("SHA3-256 is longer than 16 bytes")
+  }
+
+  fn load(
+    path: &Path,
+    today: NaiveDate,
+  ) -> std::io::Result<Self> {
+    match std::fs::File::open(path) {
+      Ok(fp) => {
+        rmp_serde::from_read(std::io::BufReader::new(fp))
+          .map_err(std::io::Error::other)
+      }
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+        Ok(Self::new(today))
+      }
+      Err(e) => Err(e),
+    }
+  }
+
+  /// 書きかけのファイルが残らないよう一時ファイルに書いてから置き換える
+  fn save(&self, path: &Path) -> std::io::Result<()> {
+    let temp = path.with_extension("tmp");
+    rmp_serde::encode::write(
+      &mut std::io::BufWriter::new(std::fs::File::create(
+        &temp,
+      )?),
+      self,
+    )
+    .map_err(std::io::Error::other)?;
+    std::fs::rename(temp, path)
+  }
+}
+
+/// 読み込み元のパスと組にしたカウンタ
+struct Counter {
+  path: PathBuf,
+  state: CounterState,
+  /// 前回の書き出しから数が変わったか
+  dirty: bool,
+}
+
+static COUNTER: Mutex<Option<Counter>> = Mutex::new(None);
+
+/// 保存済みの数を読み込み、定期的に書き出すタスクを起動する
+pub fn init(
+  config: &CounterConfig,
+) -> Result<(), Box<dyn std::error::Error>> {
+  let path = PathBuf::from(&config.file);
+  let state = CounterState::load(&path, config.today())
+    .map_err(|e| format!("{}: {e}", path.display()))?;
+  *COUNTER.lock() = Some(Counter {
+    path,
+    state,
+    dirty: false,
+  });
+  tokio::spawn(async {
+    loop {
+      let interval =
+        crate::config::get().service.counter.flush_interval_secs;
+      tokio::time::sleep(Duration::from_secs(interval)).await;
+      if let Err(e) = persist() {
+        log::error!("failed to save visitor counter: {e}");
+      }
+    }
+  });
+  Ok(())
+}
+
+/// 前回の書き出しから数が変わっていれば保存する
+pub fn persist() -> std::io::Result<()> {
+  let mut counter = COUNTER.lock();
+  let Some(counter) = counter.as_mut().filter(|c| c.dirty)
+  else {
+    return Ok(());
+  };
+  counter.state.save(&counter.path)?;
+  counter.dirty = false;
+  Ok(())
+}
+
+/// 訪問を数えた結果
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub struct Visit {
+  /// この訪問者が何人目か
+  pub number: u64,
+  /// 累計の訪問者数
+  pub total: u64,
+  /// 本日の訪問者数
+  pub daily: u64,
+}
+impl Visit {
+  /// カウンタの各桁(最小の桁数まで0で埋める)
+  pub fn odometer(&self, value: &u64) -> Vec<char> {
+    let digits = crate::config::get().service.counter.digits;
+    format!("{value:0digits$}").chars().collect()
+  }
+}
+impl<S: Send + Sync> FromRequestParts<S> for Visit {
+  type Rejection = Infallible;
+
+  async fn from_request_parts(
+    parts: &mut Parts,
+    _state: &S,
+  ) -> Result<Self, Self::Rejection> {
+    let config = crate::config::get();
+    let peer = parts
+      .extensions
+      .get::<ConnectInfo<PeerAddr>>()
+      .map(|ConnectInfo(peer)| *peer);
+    let ip = crate::access_log::client_ip(
+      peer,
+      &parts.headers,
+      &config.access_log.trusted_proxies,
+    );
+    let today = config.service.counter.today();
+    let mut counter = COUNTER.lock();
+    Ok(match counter.as_mut() {
+      Some(counter) => {
+        let (visit, counted) = counter.state.visit(ip, today);
+        counter.dirty |= counted;
+        visit
+      }
+      None => Visit::default(),
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn day(d: u32) -> NaiveDate {
+    NaiveDate::from_ymd_opt(2026, 1, d).unwrap()
+  }
+
+  #[test]
+  fn visitors_are_counted_once_a_day() {
+    let mut state = CounterState::new(day(1));
+    let a = Some("192.0.2.1".parse().unwrap());
+    let b = Some("2001:db8::1".parse().unwrap());
+    assert_eq!(state.visit(a, day(1)).0.number, 1);
+    assert_eq!(state.visit(b, day(1)).0.number, 2);
+    let (visit, counted) = state.visit(a, day(1));
+    assert!(!counted);
+    assert_eq!(
+      visit,
+      Visit {
+        number: 1,
+        total: 2,
+        daily: 2
+      }
+    );
+
+    let salt = state.salt;
+    let (visit, counted) = state.visit(a, day(2));
+    assert!(counted);
+    assert_eq!(
+      visit,
+      Visit {
+        number: 3,
+        total: 3,
+        daily: 1
+      }
+    );
+    assert_ne!(state.salt, salt);
+    assert_eq!(state.visit(None, day(2)).0.total, 3);
+  }
+
+  #[test]
+  fn ipv6_visitors_are_told_apart_by_prefix() {
+    let mut state = CounterState::new(day(1));
+    for ip in
+      ["2001:db8::1", "2001:db8::ffff:1", "2001:db8:0:0:1::"]
+    {
+      state.visit(Some(ip.parse().unwrap()), day(1));
+    }
+    assert_eq!(state.daily, 1);
+    state
+      .visit(Some("2001:db8:0:1::1".parse().unwrap()), day(1));
+    assert_eq!(state.daily, 2);
+    // IPv4射影アドレスはIPv4と同じ訪問者
+    state.visit(Some("192.0.2.1".parse().unwrap()), day(1));
+    state
+      .visit(Some("::ffff:192.0.2.1".parse().unwrap()), day(1));
+    assert_eq!(state.daily, 3);
+  }
+
+  #[test]
+  fn the_number_of_visitors_told_apart_is_bounded() {
+    let mut state = CounterState::new(day(1));
+    for i in 0..MAX_SEEN as u32 + 10 {
+      state.visit(Some(IpAddr::from(i.to_be_bytes())), day(1));
+    }
+    assert_eq!(state.seen.len(), MAX_SEEN);
+    assert_eq!(state.total, MAX_SEEN as u64);
+    // 上限の後も見分けている訪問者には前回の番号を返す
+    let (visit, counted) =
+      state.visit(Some(IpAddr::from([0, 0, 0, 1])), day(1));
+    assert!(!counted);
+    assert_eq!(visit.number, 2);
+  }
+
+  #[test]
+  fn counts_survive_a_restart_without_the_salt() {
+    let path = std::env::temp_dir()
+      .join(format!("tmdx4-counter-{}.bin", std::process::id()));
+    let ip = Some("192.0.2.1".parse().unwrap());
+    let mut state = CounterState::new(day(1));
+    state.visit(ip, day(1));
+    state.save(&path).unwrap();
+
+    let mut loaded = CounterState::load(&path, day(1)).unwrap();
+    assert_ne!(loaded.salt, state.salt);
+    assert!(loaded.seen.is_empty());
+    let (visit, counted) = loaded.visit(ip, day(1));
+    assert!(counted);
+    assert_eq!(visit.total, 2);
+    assert_eq!(visit.daily, 2);
+    std::fs::remove_file(&path).unwrap();
+  }
+}
@@ -0,0 +1,403 @@
+//! ヘッダメニュー(ﾅﾋﾞｹﾞｰｼｮﾝ・お気に入り)の項目の実装
+//!
+//! 項目は表示名・URL・アイコン・外部リンクか・並び順・親の項目を持ち、メンテナンスページで編集する。
+//! 親を持つ項目は親のサブメニューになる
+
+use std::{path::Path, sync::OnceLock};
+
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+
+use crate::error::AppError;
+
+/// ヘッダメニューについてのコンフィグ
+#[derive(Deserialize, Serialize, Clone)]
+pub struct MenusConfig {
+  /// 項目を保存するファイル
+  pub file: String,
+}
+impl Default for MenusConfig {
+  fn default() -> Self {
+    Self {
+      file: "./menus.bin".into(),
+    }
+  }
+}
+
+/// 項目を載せるメニュー
+#[derive(
+  Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq,
+)]
+pub enum MenuKind {
+  /// ﾅﾋﾞｹﾞｰｼｮﾝ(N)
+  Navigation,
+  /// お気に入り(F)
+  Favorites,
+}
+impl MenuKind {
+  pub const ALL: [Self; 2] = [Self::Navigation, Self::Favorites];
+
+  pub fn name(self) -> &'static str {
+    match self {
+      Self::Navigation => "navigation",
+      Self::Favorites => "favorites",
+    }
+  }
+
+  pub fn from_name(name: &str) -> Option<Self> {
+    Self::ALL.into_iter().find(|kind| kind.name() == name)
+  }
+}
+
+/// メニューの項目
+#[derive(
+  Serialize, Deserialize, Clone, Debug, PartialEq, Eq,
+)]
+pub struct MenuItem {
+  pub id: u64,
+  pub kind: MenuKind,
+  /// 親の項目(Noneならメニューの直下)
+  pub parent: Option<u64>,
+  /// 小さいほど上に並ぶ(同じなら追加した順)
+  pub order: i32,
+  pub label: String,
+  pub url: String,
+  pub icon: Option<String>,
+  /// 外部のサイトへのリンクか(新しいタブで開く)
+  pub external: bool,
+}
+
+/// 並べた項目(深さ優先の順)
+pub struct MenuEntry<'a> {
+  pub item: &'a MenuItem,
+  /// 子の項目があるか(あれば続けて子を並べる)
+  pub has_children: bool,
+  /// この項目の後に閉じるサブメニューの数
+  pub closes: usize,
+}
+
+/// リンク先に使えるURLか
+/// 相対URLと`http`・`https`のみ許し、`javascript:`等は拒む
+pub fn is_safe_url(url: &str) -> bool {
+  if url.is_empty()
+    || url.chars().any(|c| {
+      c.is_whitespace()
+        || c.is_control()
+        || matches!(c, '"' | '\'' | '<' | '>' | '\\' | '`')
+    })
+  {
+    return false;
+  }
+  let end = url.find(['/', '?', '#']).unwrap_or(url.len());
+  match url[..end].split_once(':') {
+    Some((scheme, _)) => {
+      scheme.eq_ignore_ascii_case("http")
+        || scheme.eq_ignore_ascii_case("https")
+    }
+    None => true,
+  }
+}
+
+/// 項目の一覧
+#[derive(Serialize, Deserialize)]
+pub struct MenuService {
+  next_id: u64,
+  items: Vec<MenuItem>,
+}
+impl Default for MenuService {
+  /// 初期の項目(サイト内のページのみ)
+  fn default() -> Self {
+    let mut service = Self {
+      next_id: 0,
+      items: Vec::new(),
+    };
+    for (label, url) in
+      [("トップ", "./"), ("お知らせ一覧", "announcements")]
+    {
+      let item = MenuItem {
+        id: 0,
+        kind: MenuKind::Navigation,
+        parent: None,
+        order: 0,
+        label: label.into(),
+        url: url.into(),
+        icon: None,
+        external: false,
+      };
+      service.upsert(None, item).expect("default menu is valid");
+    }
+    service
+  }
+}
+impl MenuService {
+  /// 項目を追加する(`id`を指定すればその項目を置き換える)
+  pub fn upsert(
+    &mut self,
+    id: Option<u64>,
+    mut item: MenuItem,
+  ) -> Result<u64, &'static str> {
+    if item.label.trim().is_empty() {
+      return Err("表示名を入力してください");
+    }
+    if !is_safe_url(&item.url) {
+      return Err("URLは相対URLかhttp(s)のURLにしてください");
+    }
+    if let Some(parent) = item.parent {
+      let Some(parent) = self.get(parent) else {
+        return Err("親の項目が見つかりません");
+      };
+      if parent.kind != item.kind {
+        return Err("親の項目は同じメニューから選んでください");
+      }
+      // 自身や子孫を親にすると辿れなくなる
+      if let Some(id) = id
+        && self.ancestors(parent.id).any(|a| a == id)
+      {
+        return Err("自身や子孫の項目は親にできません");
+      }
+    }
+    match id {
+      Some(id) => {
+        let Some(slot) =
+          self.items.iter_mut().find(|i| i.id == id)
+        else {
+          return Err("編集する項目が見つかりません");
+        };
+        item.id = id;
+        *slot = item;
+        // 別のメニューに移したら子も付いていく
+        let kind = slot.kind;
+        let descendants = self.descendants(id);
+        for item in &mut self.items {
+          if descendants.contains(&item.id) {
+            item.kind = kind;
+          }
+        }
+        Ok(id)
+      }
+      None => {
+        self.next_id += 1;
+        item.id = self.next_id;
+        self.items.push(item);
+        Ok(self.next_id)
+      }
+    }
+  }
+
+  /// 項目を子孫ごと削除する
+  pub fn remove(&mut self, id: u64) -> Option<MenuItem> {
+    let index = self.items.iter().position(|i| i.id == id)?;
+    let descendants = self.descendants(id);
+    let item = self.items.remove(index);
+    self.items.retain(|i| !descendants.contains(&i.id));
+    Some(item)
+  }
+
+  pub fn get(&self, id: u64) -> Option<&MenuItem> {
+    self.items.iter().find(|i| i.id == id)
+  }
+
+  /// 自身と祖先のID
+  fn ancestors(&self, id: u64) -> impl Iterator<Item = u64> {
+    std::iter::successors(Some(id), |id| self.get(*id)?.parent)
+  }
+
+  fn descendants(&self, id: u64) -> Vec<u64> {
+    let mut found = Vec::new();
+    let mut stack = vec![id];
+    while let Some(parent) = stack.pop() {
+      for item in &self.items {
+        if item.parent == Some(parent) {
+          found.push(item.id);
+          stack.push(item.id);
+        }
+      }
+    }
+    found
+  }
+
+  fn children(
+    &self,
+    kind: MenuKind,
+    parent: Option<u64>,
+  ) -> Vec<&MenuItem> {
+    let mut children = self
+      .items
+      .iter()
+      .filter(|i| i.kind == kind && i.parent == parent)
+      .collect::<Vec<_>>();
+    children.sort_by_key(|i| (i.order, i.id));
+    children
+  }
+
+  /// メニューの項目を深さ優先で並べる
+  pub fn entries(&self, kind: MenuKind) -> Vec<MenuEntry<'_>> {
+    let mut entries = Vec::new();
+    let mut stack = self
+      .children(kind, None)
+      .into_iter()
+      .map(|item| (item, 0))
+      .collect::<Vec<_>>();
+    stack.reverse();
+    while let Some((item, depth)) = stack.pop() {
+      let children = self.children(kind, Some(item.id));
+      entries.push(MenuEntry {
+        item,
+        has_children: !children.is_empty(),
+        closes: 0,
+      });
+      if children.is_empty() {
+        // 次の項目の深さまでサブメニューを閉じる
+        let next = stack.last().map_or(0, |(_, depth)| *depth);
+        entries.last_mut().unwrap().closes = depth - next;
+      }
+      stack.extend(
+        children
+          .into_iter()
+          .rev()
+          .map(|child| (child, depth + 1)),
+      );
+    }
+    entries
+  }
+
+  /// 全ての項目(メニュー・ID順)
+  pub fn all(&self) -> Vec<&MenuItem> {
+    let mut all = self.items.iter().collect::<Vec<_>>();
+    all.sort_by_key(|i| {
+      (
+        MenuKind::ALL.iter().position(|kind| *kind == i.kind),
+        i.id,
+      )
+    });
+    all
+  }
+
+  /// 保存済みの項目を読み込む。無ければ初期の項目にする
+  pub fn load(path: &Path) -> Result<Self, AppError> {
+    match std::fs::File::open(path) {
+      Ok(fp) => {
+        Ok(rmp_serde::from_read(std::io::BufReader::new(fp))?)
+      }
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+        Ok(Self::default())
+      }
+      Err(e) => Err(AppError::Io(e)),
+    }
+  }
+
+  /// 書きかけのファイルが残らないよう一時ファイルに書いてから置き換える
+  pub fn save(&self, path: &Path) -> Result<(), AppError> {
+    let temp = path.with_extension("tmp");
+    rmp_serde::encode::write(
+      &mut std::io::BufWriter::new(std::fs::File::create(
+        &temp,
+      )?),
+      self,
+    )?;
+    std::fs::rename(temp, path)?;
+    Ok(())
+  }
+}
+
+/// サーバで共有するメニュー
+static MENUS: OnceLock<RwLock<MenuService>> = OnceLock::new();
+
+/// 保存済みの項目を読み込んでサーバで共有する
+pub fn init(
+  config: &MenusConfig,
+) -> Result<(), Box<dyn std::error::Error>> {
+  let service = MenuService::load(Path::new(&config.file))?;
+  let _ = MENUS.set(RwLock::new(service));
+  Ok(())
+}
+
+/// サーバで共有しているメニュー
+pub fn menus() -> &'static RwLock<MenuService> {
+  MENUS.get().expect("menu service is not initialized")
+}
+
+/// 共有しているメニューを保存する
+pub fn persist(
+  config: &MenusConfig,
+) -> Result<(), Box<dyn std::error::Error>> {
+  if let Some(menus) = MENUS.get() {
+    menus.read().save(Path::new(&config.file))?;
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn item(
+    label: &str,
+    parent: Option<u64>,
+    order: i32,
+  ) -> MenuItem {
+    MenuItem {
+      id: 0,
+      kind: MenuKind::Favorites,
+      parent,
+      order,
+      label: label.into(),
+      url: "https://example.com/".into(),
+      icon: None,
+      external: true,
+    }
+  }
+
+  #[test]
+  fn nested_items_are_listed_depth_first() {
+    let mut service = MenuService::default();
+    let b = service.upsert(None, item("b", None, 2)).unwrap();
+    let a = service.upsert(None, item("a", None, 1)).unwrap();
+    let a1 =
+      service.upsert(None, item("a1", Some(a), 0)).unwrap();
+    service.upsert(None, item("a1x", Some(a1), 0)).unwrap();
+    service.upsert(None, item("a2", Some(a), 1)).unwrap();
+    let listed = service
+      .entries(MenuKind::Favorites)
+      .iter()
+      .map(|e| (e.item.label.as_str(), e.has_children, e.closes))
+      .collect::<Vec<_>>();
+    assert_eq!(
+      listed,
+      [
+        ("a", true, 0),
+        ("a1", true, 0),
+        ("a1x", false, 1),
+        ("a2", false, 1),
+        ("b", false, 0),
+      ]
+    );
+
+    assert_eq!(
+      service.upsert(Some(a), item("a", Some(a1), 0)),
+      Err("自身や子孫の項目は親にできません")
+    );
+    service.remove(a);
+    assert_eq!(service.entries(MenuKind::Favorites).len(), 1);
+    assert!(service.get(b).is_some());
+  }
+
+  #[test]
+  fn only_http_and_relative_urls_are_allowed() {
+    for url in
+      ["./", "announcements", "/a?b=c:d", "https://x.jp/"]
+    {
+      assert!(is_safe_url(url), "{url}");
+    }
+    for url in [
+      "javascript:alert(1)",
+      "JaVaScRiPt:x",
+      "data:text/html,x",
+      "a b",
+      "x'y",
+      "",
+    ] {
+      assert!(!is_safe_url(url), "{url}");
+    }
+  }
+}
@@ -6,9 +6,36 @@ use serde::{Deserialize, Serialize};
 pub struct ServiceConfig {
   pub articles: article::ArticlesConfig,
   pub assets: AssetConfig,
+  pub counter: counter::CounterConfig,
+  pub announcements: announcement::AnnouncementsConfig,
+  pub menus: menu::MenusConfig,
 }
 
+pub mod announcement;
 pub mod article;
+pub mod counter;
+pub mod menu;
+
+/// サーバで共有するサービスの状態を読み込む
+pub fn init(
+  config: &ServiceConfig,
+) -> Result<(), Box<dyn std::error::Error>> {
+  article::init(&config.articles)?;
+  announcement::init(&config.announcements)?;
+  menu::init(&config.menus)?;
+  counter::init(&config.counter)
+}
+
+/// サーバで共有するサービスの状態を保存する
+pub fn persist(
+  config: &ServiceConfig,
+) -> Result<(), Box<dyn std::error::Error>> {
+  article::persist(&config.articles)?;
+  announcement::persist(&config.announcements)?;
+  menu::persist(&config.menus)?;
+  counter::persist()?;
+  Ok(())
+}
 
 #[derive(Deserialize, Serialize)]
 pub struct AssetConfig {
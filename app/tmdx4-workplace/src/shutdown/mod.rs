@@ -0,0 +1,40 @@
+//! 終了処理の実装
+//!
+//! SIGTERM/SIGINTで新規の受け付けを止め、処理中のリクエストを期限付きで待ってから
+//! メモリ上の状態の保存とログの吐き出しを行う
+
+use std::time::Duration;
+
+use tokio::signal::unix::{SignalKind, signal};
+
+/// SIGTERMもしくはSIGINTを受け取るまで待つ
+pub async fn signal_received() {
+  let mut terminate = match signal(SignalKind::terminate()) {
+    Ok(terminate) => terminate,
+    Err(e) => {
+      log::error!("cannot install SIGTERM handler: {e}");
+      let _ = tokio::signal::ctrl_c().await;
+      return;
+    }
+  };
+  tokio::select! {
+    _ = terminate.recv() => log::info!("SIGTERM received"),
+    _ = tokio::signal::ctrl_c() => log::info!("SIGINT received"),
+  }
+}
+
+/// 処理中のリクエストを待つ期限
+pub fn drain_timeout() -> Duration {
+  Duration::from_secs(crate::config::get().shutdown_timeout_secs)
+}
+
+/// メモリ上の状態を保存し、ログを吐き出す
+pub fn finalize() {
+  let config = crate::config::get();
+  if let Err(e) = crate::service::persist(&config.service) {
+    log::error!("failed to persist service state: {e}");
+  }
+  log::info!("shutdown complete");
+  crate::access_log::flush();
+  crate::logging::flush();
+}
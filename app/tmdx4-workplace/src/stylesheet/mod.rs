@@ -0,0 +1,156 @@
+//! スタイルシートの配信の実装
+//!
+//! 埋め込んだCSSを内容のハッシュを含むURL(`/static/common.<hash>.css`)で配信し、
+//! URLが変わらない限り内容も変わらないので無期限にキャッシュさせる。
+//! 開発モードではディスクから毎回読み込み、編集がすぐ反映されるようにする
+
+use std::{path::Path, sync::LazyLock};
+
+use axum::{
+  Router,
+  extract::Path as UrlPath,
+  http::{HeaderValue, StatusCode, header},
+  response::{IntoResponse, Response},
+  routing::get,
+};
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Sha3_256};
+
+/// ハッシュが一致したものに付ける`Cache-Control`
+const IMMUTABLE: &str = "public, max-age=31536000, immutable";
+
+/// 配信するスタイルシート
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Stylesheet {
+  Common,
+  Main,
+  Mainte,
+}
+impl Stylesheet {
+  pub const ALL: [Self; 3] =
+    [Self::Common, Self::Main, Self::Mainte];
+
+  /// URLとファイル名に使う名前
+  pub fn name(self) -> &'static str {
+    match self {
+      Self::Common => "common",
+      Self::Main => "main",
+      Self::Mainte => "mainte",
+    }
+  }
+
+  fn embedded(self) -> &'static str {
+    match self {
+      Self::Common => include_str!("../styles/common.css"),
+      Self::Main => include_str!("../styles/main.css"),
+      Self::Mainte => include_str!("../styles/mainte.css"),
+    }
+  }
+
+  fn from_name(name: &str) -> Option<Self> {
+    Self::ALL.into_iter().find(|sheet| sheet.name() == name)
+  }
+}
+
+/// スタイルシートの配信についてのコンフィグ
+#[derive(Deserialize, Serialize, Clone, Default)]
+pub struct StylesheetConfig {
+  /// 開発モードでCSSを読み込むディレクトリ(`src/styles`等、Noneで埋め込みを使う)
+  pub dev_dir: Option<String>,
+}
+impl StylesheetConfig {
+  /// コンフィグの内容を検証し、見つかった問題を`problems`に追加する
+  pub fn validate(&self, problems: &mut Vec<String>) {
+    if let Some(dir) = &self.dev_dir
+      && !Path::new(dir).is_dir()
+    {
+      problems.push(format!(
+        "stylesheet.dev_dir: ディレクトリ{dir}がありません"
+      ));
+    }
+  }
+}
+
+fn hash(css: &str) -> String {
+  hex::encode(&Sha3_256::digest(css)[..8])
+}
+
+/// 埋め込んだCSSのハッシュ
+static EMBEDDED_HASHES: LazyLock<[String; 3]> =
+  LazyLock::new(|| {
+    Stylesheet::ALL.map(|sheet| hash(sheet.embedded()))
+  });
+
+/// 現在の内容とそのハッシュ
+/// 開発モードで読み込めなければ埋め込んだものを使う
+fn current(sheet: Stylesheet) -> (String, String) {
+  let config = crate::config::get();
+  if let Some(dir) = &config.stylesheet.dev_dir {
+    let file =
+      Path::new(dir).join(format!("{}.css", sheet.name()));
+    match std::fs::read_to_string(&file) {
+      Ok(css) => {
+        let hash = hash(&css);
+        return (css, hash);
+      }
+      Err(e) => log::warn!(
+        "cannot read {}, using the embedded one: {e}",
+        file.display()
+      ),
+    }
+  }
+  (
+    sheet.embedded().to_string(),
+    EMBEDDED_HASHES[sheet as usize].clone(),
+  )
+}
+
+/// ページから参照するURL
+pub fn href(sheet: Stylesheet) -> String {
+  let hash = match crate::config::get().stylesheet.dev_dir {
+    Some(_) => current(sheet).1,
+    None => EMBEDDED_HASHES[sheet as usize].clone(),
+  };
+  format!("/static/{}.{hash}.css", sheet.name())
+}
+
+/// `/static/<name>.<hash>.css`を返す
+/// ハッシュが古い場合(更新前のページから参照された等)は現在の内容をキャッシュさせずに返す
+async fn serve_stylesheet(
+  UrlPath(file): UrlPath<String>,
+) -> Response {
+  let Some((sheet, requested)) = file
+    .strip_suffix(".css")
+    .and_then(|stem| stem.split_once('.'))
+    .and_then(|(name, hash)| {
+      Some((Stylesheet::from_name(name)?, hash))
+    })
+  else {
+    return crate::bsod::Bsod::new(StatusCode::NOT_FOUND)
+      .into_response();
+  };
+  let (css, hash) = current(sheet);
+  let dev = crate::config::get().stylesheet.dev_dir.is_some();
+  let cache_control = if requested == hash && !dev {
+    IMMUTABLE
+  } else {
+    "no-cache"
+  };
+  let mut response = (
+    [
+      (header::CONTENT_TYPE, "text/css; charset=utf-8"),
+      (header::CACHE_CONTROL, cache_control),
+    ],
+    css,
+  )
+    .into_response();
+  if let Ok(etag) = HeaderValue::try_from(format!("\"{hash}\""))
+  {
+    response.headers_mut().insert(header::ETAG, etag);
+  }
+  response
+}
+
+pub(crate) fn stylesheet_serve() -> Router {
+  Router::new().route("/{file}", get(serve_stylesheet))
+}
@@ -0,0 +1,349 @@
+//! テーマ(配色)の実装
+//!
+//! テーマはCSS変数の組とメタデータ(名前・アイコン・暗い配色か)をJSONで書いたもので、
+//! 組み込みの昼間・夜間に加えて`themes.dir`の`<ID>.json`を読み込む。
+//! 全てのテーマのCSSをページに埋め込み、ラジオボタンの選択で切り替える。
+//! テーマファイルの変更はコンフィグの再読み込みで反映する
+
+use std::{
+  collections::BTreeMap,
+  path::Path,
+  sync::{Arc, LazyLock},
+};
+
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+
+use crate::html::Trusted;
+
+/// 自動切替を表すID(テーマのIDには使えない)
+pub const AUTO: &str = "auto";
+
+/// 組み込みのテーマ(同じIDのファイルを`themes.dir`に置けば差し替えられる)
+const BUILTIN: [(&str, &str); 2] = [
+  ("daytime", include_str!("../themes/daytime.json")),
+  ("night", include_str!("../themes/night.json")),
+];
+
+/// テーマファイルの内容
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ThemeFile {
+  name: String,
+  icon: String,
+  #[serde(default)]
+  dark: bool,
+  variables: BTreeMap<String, String>,
+}
+
+/// テーマ
+pub struct Theme {
+  /// ファイル名から拡張子を除いたもの
+  pub id: String,
+  /// 表示(V)メニューに出す名前
+  pub name: String,
+  /// タイトル行のボタンに出す文字
+  pub icon: String,
+  /// 暗い配色か(自動切替で夜間・ダークモードに使う)
+  pub dark: bool,
+  variables: BTreeMap<String, String>,
+}
+impl Theme {
+  fn parse(id: &str, source: &str) -> Result<Self, String> {
+    if id.is_empty()
+      || id == AUTO
+      || !id.bytes().all(|b| {
+        b.is_ascii_lowercase()
+          || b.is_ascii_digit()
+          || matches!(b, b'_' | b'-')
+      })
+    {
+      return Err(format!(
+        "テーマのIDは[a-z0-9_-]のみで\"{AUTO}\"以外にしてください"
+      ));
+    }
+    let file: ThemeFile =
+      serde_json::from_str(source).map_err(|e| e.to_string())?;
+    if file.name.trim().is_empty() || file.icon.trim().is_empty()
+    {
+      return Err("nameとiconを入力してください".into());
+    }
+    for (name, value) in &file.variables {
+      let valid_name =
+        name.strip_prefix("--").is_some_and(|rest| {
+          !rest.is_empty()
+            && rest.bytes().all(|b| {
+              b.is_ascii_alphanumeric()
+                || matches!(b, b'_' | b'-')
+            })
+        });
+      if !valid_name {
+        return Err(format!(
+          "{name}: CSSのカスタムプロパティではありません"
+        ));
+      }
+      // `<style>`の外やほかの宣言に及ばないようにする
+      if value.trim().is_empty()
+        || value
+          .contains([';', '{', '}', '<', '>', '\\', '\n', '\r'])
+        || value.contains("/*")
+      {
+        return Err(format!("{name}: 値{value:?}は使えません"));
+      }
+    }
+    Ok(Self {
+      id: id.into(),
+      name: file.name,
+      icon: file.icon,
+      dark: file.dark,
+      variables: file.variables,
+    })
+  }
+
+  /// 選ばれた時(自動切替で決まった時を含む)に変数を上書きするCSS
+  fn css(&self) -> String {
+    let mut css = format!(
+      ":root:has(input#theme-{id}:checked),\n\
+        :root:has(input#theme-{AUTO}[data-resolved='{id}']:checked) {{\n",
+      id = self.id
+    );
+    for (name, value) in &self.variables {
+      css += &format!("  {name}: {value};\n");
+    }
+    css += "}\n";
+    css
+  }
+}
+
+/// テーマについてのコンフィグ
+#[derive(Deserialize, Serialize, Clone)]
+pub struct ThemesConfig {
+  /// テーマファイル(`<ID>.json`)を置くディレクトリ(Noneなら組み込みのみ)
+  pub dir: Option<String>,
+
+  /// 自動切替で明るい配色の時に使うテーマ
+  pub auto_light: String,
+
+  /// 自動切替で暗い配色の時に使うテーマ
+  pub auto_dark: String,
+}
+impl Default for ThemesConfig {
+  fn default() -> Self {
+    Self {
+      dir: None,
+      auto_light: "daytime".into(),
+      auto_dark: "night".into(),
+    }
+  }
+}
+impl ThemesConfig {
+  /// コンフィグの内容とテーマファイルを検証し、見つかった問題を`problems`に追加する
+  pub fn validate(&self, problems: &mut Vec<String>) {
+    if let Err(found) = Themes::load(self) {
+      problems.extend(found);
+    }
+  }
+}
+
+/// 読み込んだテーマの一覧
+pub struct Themes {
+  list: Vec<Theme>,
+  css: String,
+  auto_light: String,
+  auto_dark: String,
+}
+impl Themes {
+  fn load(config: &ThemesConfig) -> Result<Self, Vec<String>> {
+    let mut problems = Vec::new();
+    let mut list = Vec::new();
+    let mut add = |theme: Theme| match list
+      .iter_mut()
+      .find(|t: &&mut Theme| t.id == theme.id)
+    {
+      Some(slot) => *slot = theme,
+      None => list.push(theme),
+    };
+    for (id, source) in BUILTIN {
+      match Theme::parse(id, source) {
+        Ok(theme) => add(theme),
+        Err(e) => {
+          problems.push(format!("組み込みのテーマ{id}: {e}"))
+        }
+      }
+    }
+    if let Some(dir) = &config.dir {
+      match std::fs::read_dir(dir) {
+        Ok(entries) => {
+          let mut files = entries
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|path| {
+              path.extension().is_some_and(|ext| ext == "json")
+            })
+            .collect::<Vec<_>>();
+          files.sort();
+          for path in files {
+            match Self::load_file(&path) {
+              Ok(theme) => add(theme),
+              Err(e) => problems.push(format!(
+                "themes.dir: {}: {e}",
+                path.display()
+              )),
+            }
+          }
+        }
+        Err(e) => problems.push(format!(
+          "themes.dir: {dir}を読み込めません: {e}"
+        )),
+      }
+    }
+    for (key, id) in [
+      ("auto_light", &config.auto_light),
+      ("auto_dark", &config.auto_dark),
+    ] {
+      if !list.iter().any(|theme| theme.id == *id) {
+        problems
+          .push(format!("themes.{key}: テーマ{id}がありません"));
+      }
+    }
+    if !problems.is_empty() {
+      return Err(problems);
+    }
+    Ok(Self {
+      css: list.iter().map(Theme::css).collect(),
+      list,
+      auto_light: config.auto_light.clone(),
+      auto_dark: config.auto_dark.clone(),
+    })
+  }
+
+  fn load_file(path: &Path) -> Result<Theme, String> {
+    let id = path
+      .file_stem()
+      .and_then(|stem| stem.to_str())
+      .unwrap_or_default();
+    let source = std::fs::read_to_string(path)
+      .map_err(|e| e.to_string())?;
+    Theme::parse(id, &source)
+  }
+
+  pub fn iter(&self) -> impl Iterator<Item = &Theme> {
+    self.list.iter()
+  }
+
+  pub fn get(&self, id: &str) -> Option<&Theme> {
+    self.list.iter().find(|theme| theme.id == id)
+  }
+
+  /// 自動切替で使うテーマのID
+  pub fn auto(&self, dark: bool) -> &str {
+    if dark {
+      &self.auto_dark
+    } else {
+      &self.auto_light
+    }
+  }
+
+  /// 全てのテーマのCSS(値は読み込み時に検証済み)
+  pub fn css(&self) -> Trusted<&str> {
+    Trusted::theme_css(self)
+  }
+
+  /// [`Trusted::theme_css`]で包む前のCSS
+  pub(crate) fn validated_css(&self) -> &str {
+    &self.css
+  }
+}
+
+static THEMES: LazyLock<RwLock<Arc<Themes>>> =
+  LazyLock::new(|| {
+    RwLock::new(Arc::new(
+      Themes::load(&ThemesConfig::default())
+        .expect("built-in themes must be valid"),
+    ))
+  });
+
+/// 現在のテーマの一覧
+pub fn get() -> Arc<Themes> {
+  THEMES.read().clone()
+}
+
+/// コンフィグに従ってテーマを読み込み直す
+/// 読み込めなければ以前のものを使い続ける
+pub fn reconfigure(config: &crate::config::Config) {
+  match Themes::load(&config.themes) {
+    Ok(themes) => *THEMES.write() = Arc::new(themes),
+    Err(problems) => {
+      for problem in problems {
+        log::error!("cannot load themes: {problem}");
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn builtin_themes_are_valid() {
+    let themes = Themes::load(&ThemesConfig::default()).unwrap();
+    assert!(themes.get("daytime").is_some_and(|t| !t.dark));
+    assert!(themes.get("night").is_some_and(|t| t.dark));
+    assert_eq!(themes.auto(true), "night");
+  }
+
+  #[test]
+  fn values_cannot_escape_the_style_block() {
+    for value in [
+      "red; } body { display: none",
+      "red</style><script>",
+      "red /* comment",
+      "url(\\61)",
+    ] {
+      let source = serde_json::json!({
+        "name": "bad",
+        "icon": "x",
+        "variables": { "--font-color": value },
+      })
+      .to_string();
+      assert!(Theme::parse("bad", &source).is_err(), "{value}");
+    }
+  }
+
+  #[test]
+  fn ids_and_names_are_checked() {
+    let source =
+      r#"{"name": "a", "icon": "b", "variables": {}}"#;
+    assert!(Theme::parse("sepia", source).is_ok());
+    assert!(Theme::parse(AUTO, source).is_err());
+    assert!(Theme::parse("Sepia'", source).is_err());
+    let source = r#"{"name": "a", "icon": "b", "variables": {"color": "red"}}"#;
+    assert!(Theme::parse("sepia", source).is_err());
+  }
+
+  #[test]
+  fn theme_files_are_validated_at_load() {
+    let dir = std::env::temp_dir()
+      .join(format!("tmdx4-themes-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(
+      dir.join("sepia.json"),
+      r##"{"name": "セピア", "icon": "◎", "variables": {"--font-color": "#442200"}}"##,
+    )
+    .unwrap();
+    let config = ThemesConfig {
+      dir: Some(dir.to_string_lossy().into_owned()),
+      ..ThemesConfig::default()
+    };
+    let themes = Themes::load(&config).unwrap();
+    assert_eq!(
+      themes.iter().map(|t| t.id.as_str()).collect::<Vec<_>>(),
+      ["daytime", "night", "sepia"]
+    );
+
+    std::fs::write(dir.join("broken.json"), "{").unwrap();
+    let problems = Themes::load(&config).err().unwrap();
+    assert!(problems[0].contains("broken.json"));
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+}
@@ -135,10 +135,7 @@ impl std::fmt::Display for UserIdent {
       .iter()
       .flat_map(|c| [c & 0xF0, c & 0x0F].into_iter())
       .filter_map(|c| char::from_digit(c as u32, 16))
-      .fold(std::fmt::Result::Ok(()), |i, v| match i {
-        Ok(_) => f.write_char(v),
-        Err(e) => Err(e),
-      })
+      .try_for_each(|c| f.write_char(c))
   }
 }
 impl UserIdent {
@@ -190,6 +187,48 @@ pub struct UserDataConfig {
   pub argon2_p_cost: u32,
 }
 impl UserDataConfig {
+  /// ユーザ識別子からセキュリティデータとユーザデータのパスを生成する
+  fn data_paths(&self, ident: &UserIdent) -> (String, String) {
+    let mut sec_data_path =
+      String::with_capacity(self.sec_data_path.len() + 24);
+    sec_data_path += self.sec_data_path.as_str();
+    sec_data_path.push('/');
+    let mut user_data_path =
+      String::with_capacity(self.user_data_path.len() + 24);
+    user_data_path += self.user_data_path.as_str();
+    user_data_path.push('/');
+    ident.iter_hex(|c| {
+      sec_data_path.push(c);
+      user_data_path.push(c);
+    });
+    sec_data_path += ".bin";
+    user_data_path += ".bin";
+    (sec_data_path, user_data_path)
+  }
+
+  /// 新しいソルトでパスワードをハッシュ化する
+  fn hash_password(
+    &self,
+    pswd: &str,
+  ) -> Result<PasswordHashString, UserDataError> {
+    let argon2 = argon2::Argon2::new(
+      argon2::Algorithm::Argon2id,
+      argon2::Version::V0x13,
+      self
+        .init_argon2_param()
+        .map_err(UserDataError::Argon2Error)?,
+    );
+    let salt = PRNG.with(|prng| {
+      argon2::password_hash::SaltString::generate(
+        &mut *prng.borrow_mut(),
+      )
+    });
+    let hash = argon2
+      .hash_password(pswd.trim().as_bytes(), salt.as_salt())
+      .map_err(UserDataError::PasswordHashError)?;
+    Ok(PasswordHashString::from(hash))
+  }
+
   pub fn init_argon2_param(
     &self,
   ) -> Result<argon2::Params, argon2::Error> {
@@ -252,11 +291,11 @@ where
           (
             std::io::ErrorKind::NotFound,
             std::io::ErrorKind::NotFound,
-          ) => return Ok(false),
-          _ => return Err(Box::from(se)),
+          ) => Ok(false),
+          _ => Err(Box::from(se)),
         }
       }
-      (Err(e), _) | (_, Err(e)) => return Err(Box::from(e)),
+      (Err(e), _) | (_, Err(e)) => Err(Box::from(e)),
     }
   }
 
@@ -266,22 +305,8 @@ where
   ) -> Result<bool, Box<dyn std::error::Error>> {
     // パスを生成する
     let ident = UserIdent::generate(id)?;
-    let sec_data_path_len = configure.sec_data_path.len() + 24;
-    let mut sec_data_path =
-      String::with_capacity(sec_data_path_len);
-    sec_data_path += configure.sec_data_path.as_str();
-    sec_data_path.push('/');
-    let user_data_path_len = configure.user_data_path.len() + 24;
-    let mut user_data_path =
-      String::with_capacity(user_data_path_len);
-    user_data_path += configure.user_data_path.as_str();
-    user_data_path.push('/');
-    ident.iter_hex(|c| {
-      sec_data_path.push(c);
-      user_data_path.push(c);
-    });
-    sec_data_path += ".bin";
-    user_data_path += ".bin";
+    let (sec_data_path, user_data_path) =
+      configure.data_paths(&ident);
     let r = std::fs::exists(&sec_data_path)?
       && std::fs::exists(&user_data_path)?;
     Ok(r)
@@ -299,22 +324,8 @@ where
   ) -> Result<Option<Self>, UserDataError> {
     // パスを生成する
     let ident = UserIdent::generate(id)?;
-    let sec_data_path_len = configure.sec_data_path.len() + 24;
-    let mut sec_data_path =
-      String::with_capacity(sec_data_path_len);
-    sec_data_path += configure.sec_data_path.as_str();
-    sec_data_path.push('/');
-    let user_data_path_len = configure.user_data_path.len() + 24;
-    let mut user_data_path =
-      String::with_capacity(user_data_path_len);
-    user_data_path += configure.user_data_path.as_str();
-    user_data_path.push('/');
-    ident.iter_hex(|c| {
-      sec_data_path.push(c);
-      user_data_path.push(c);
-    });
-    sec_data_path += ".bin";
-    user_data_path += ".bin";
+    let (sec_data_path, user_data_path) =
+      configure.data_paths(&ident);
 
     // セキュリティデータを読み込む
     // NotFoundならNone, そうでないならデータありとする
@@ -332,7 +343,7 @@ where
     // ユーザデータを読み込む
     // NotFoundならNone, そうでないならデータありとする
     let user_data = match std::fs::File::open(&user_data_path)
-      .map(|fp| std::io::BufReader::new(fp))
+      .map(std::io::BufReader::new)
     {
       Ok(rdr) => Some(rdr),
       Err(e) => match e.kind() {
@@ -354,7 +365,7 @@ where
       argon2::Version::V0x13,
       configure
         .init_argon2_param()
-        .map_err(|e| UserDataError::Argon2Error(e))?,
+        .map_err(UserDataError::Argon2Error)?,
     );
 
     // セキュリティデータのデシリアライズ
@@ -362,7 +373,7 @@ where
       // ファイルがあれば内容を読み取る
       sec_data
         .read_to_string(&mut secure_data_buffer)
-        .map_err(|e| UserDataError::UserDataLoadError(e))?;
+        .map_err(UserDataError::UserDataLoadError)?;
     } else {
       // ファイルがないならダミーを作る
       secure_data_buffer
@@ -385,7 +396,7 @@ where
     let hash = argon2::password_hash::PasswordHash::new(
       &secure_data_buffer,
     )
-    .map_err(|e| UserDataError::PasswordHashError(e))?;
+    .map_err(UserDataError::PasswordHashError)?;
     let salt = hash.salt.unwrap();
 
     let comp_hash =
@@ -394,7 +405,7 @@ where
         pswd.trim(),
         salt,
       )
-      .map_err(|e| UserDataError::PasswordHashError(e))?;
+      .map_err(UserDataError::PasswordHashError)?;
 
     // ハッシュ比較して違ったらOk(None)
     if hash != comp_hash {
@@ -420,20 +431,19 @@ where
     // 再ハッシュしたのを書き込む
     std::io::BufWriter::new(
       std::fs::File::create(&sec_data_path)
-        .map_err(|e| UserDataError::UserDataSaveError(e))?,
+        .map_err(UserDataError::UserDataSaveError)?,
     )
     .write(pswd_hash.as_bytes())
-    .map_err(|e| UserDataError::UserDataSaveError(e))?;
+    .map_err(UserDataError::UserDataSaveError)?;
 
     // ユーザデータの読み込み
-    let user_data = match user_data
-      .map(|rdr| rmp_serde::from_read(rdr))
-    {
-      Some(Err(e)) => Err(UserDataError::MPackDecodeError(e)),
-      Some(Ok(v)) => Ok(v),
-      None => user_data_init_func()
-        .map_err(|e| UserDataError::UserDataInitializeError(e)),
-    }?;
+    let user_data =
+      match user_data.map(|rdr| rmp_serde::from_read(rdr)) {
+        Some(Err(e)) => Err(UserDataError::MPackDecodeError(e)),
+        Some(Ok(v)) => Ok(v),
+        None => user_data_init_func()
+          .map_err(UserDataError::UserDataInitializeError),
+      }?;
 
     Ok(Some(Self {
       ident,
@@ -448,22 +458,7 @@ where
     configure: &UserDataConfig,
   ) -> Result<Self, UserDataError> {
     let ident = UserIdent::generate(name)?;
-    let argon2 = argon2::Argon2::new(
-      argon2::Algorithm::Argon2id,
-      argon2::Version::V0x13,
-      configure
-        .init_argon2_param()
-        .map_err(|e| UserDataError::Argon2Error(e))?,
-    );
-    let salt = PRNG.with(|prng| {
-      argon2::password_hash::SaltString::generate(
-        &mut *prng.borrow_mut(),
-      )
-    });
-    let hash = argon2
-      .hash_password(pswd.trim().as_bytes(), salt.as_salt())
-      .map_err(|e| UserDataError::PasswordHashError(e))?;
-    let hash = PasswordHashString::from(hash);
+    let hash = configure.hash_password(pswd)?;
     Ok(Self {
       ident,
       pswd_hash: hash,
@@ -476,51 +471,100 @@ where
     configure: &UserDataConfig,
   ) -> Result<(), UserDataError> {
     if !std::fs::exists(&configure.sec_data_path)
-      .map_err(|e| UserDataError::UserDataSaveError(e))?
+      .map_err(UserDataError::UserDataSaveError)?
     {
       std::fs::create_dir_all(&configure.sec_data_path)
-        .map_err(|e| UserDataError::UserDataSaveError(e))?
+        .map_err(UserDataError::UserDataSaveError)?
     }
     if !std::fs::exists(&configure.user_data_path)
-      .map_err(|e| UserDataError::UserDataSaveError(e))?
+      .map_err(UserDataError::UserDataSaveError)?
     {
       std::fs::create_dir_all(&configure.user_data_path)
-        .map_err(|e| UserDataError::UserDataSaveError(e))?
+        .map_err(UserDataError::UserDataSaveError)?
     }
-    let mut buffer =
-      String::with_capacity(&configure.sec_data_path.len() + 21);
-    let mut buffer2 = String::with_capacity(
-      &configure.user_data_path.len() + 21,
-    );
-    buffer += &configure.sec_data_path;
-    buffer2 += &configure.user_data_path;
-    buffer.push('/');
-    buffer2.push('/');
-    for ch in self
-      .ident
-      .0
-      .iter()
-      .flat_map(|b| [b & 0xF0, b & 0x0F].into_iter())
-      .filter_map(|b| char::from_digit(b as u32, 16))
-    {
-      buffer.push(ch);
-      buffer2.push(ch);
-    }
-    buffer += ".bin";
-    buffer2 += ".bin";
+    let (sec_data_path, user_data_path) =
+      configure.data_paths(&self.ident);
     let mut wrt = std::io::BufWriter::new(
-      std::fs::File::create(buffer)
-        .map_err(|e| UserDataError::UserDataSaveError(e))?,
+      std::fs::File::create(sec_data_path)
+        .map_err(UserDataError::UserDataSaveError)?,
     );
     wrt
       .write(self.pswd_hash.as_str().as_bytes())
-      .map_err(|e| UserDataError::UserDataSaveError(e))?;
+      .map_err(UserDataError::UserDataSaveError)?;
     let mut wrt = std::io::BufWriter::new(
-      std::fs::File::create(buffer2)
-        .map_err(|e| UserDataError::UserDataSaveError(e))?,
+      std::fs::File::create(user_data_path)
+        .map_err(UserDataError::UserDataSaveError)?,
     );
     rmp_serde::encode::write(&mut wrt, &self.user_data)
-      .map_err(|e| UserDataError::MPackEncodeError(e))?;
+      .map_err(UserDataError::MPackEncodeError)?;
     Ok(())
   }
+
+  /// 保存されているユーザ識別子の一覧を取得する
+  /// (ユーザ名はハッシュ化されているので元の名前は復元できない)
+  pub fn list_idents(
+    configure: &UserDataConfig,
+  ) -> Result<Vec<String>, UserDataError> {
+    let dir = match std::fs::read_dir(&configure.sec_data_path) {
+      Ok(dir) => dir,
+      Err(e) => match e.kind() {
+        std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        _ => return Err(UserDataError::UserDataLoadError(e)),
+      },
+    };
+    let mut idents = Vec::new();
+    for entry in dir {
+      let path =
+        entry.map_err(UserDataError::UserDataLoadError)?.path();
+      if path.extension().is_some_and(|ext| ext == "bin")
+        && let Some(stem) = path.file_stem()
+      {
+        idents.push(stem.to_string_lossy().into_owned());
+      }
+    }
+    idents.sort();
+    Ok(idents)
+  }
+
+  /// 旧パスワードの照合なしにパスワードを再設定する(管理用)
+  /// ユーザが存在しなければOk(false)
+  pub fn reset_password(
+    id: &str,
+    new_pswd: &str,
+    configure: &UserDataConfig,
+  ) -> Result<bool, UserDataError> {
+    let ident = UserIdent::generate(id)?;
+    let (sec_data_path, _) = configure.data_paths(&ident);
+    if !std::fs::exists(&sec_data_path)
+      .map_err(UserDataError::UserDataLoadError)?
+    {
+      return Ok(false);
+    }
+    let pswd_hash = configure.hash_password(new_pswd)?;
+    std::fs::write(&sec_data_path, pswd_hash.as_str())
+      .map_err(UserDataError::UserDataSaveError)?;
+    Ok(true)
+  }
+
+  /// ユーザを削除する
+  /// ユーザが存在しなければOk(false)
+  pub fn delete(
+    id: &str,
+    configure: &UserDataConfig,
+  ) -> Result<bool, UserDataError> {
+    let ident = UserIdent::generate(id)?;
+    let (sec_data_path, user_data_path) =
+      configure.data_paths(&ident);
+    let mut removed = false;
+    for path in [sec_data_path, user_data_path] {
+      match std::fs::remove_file(&path) {
+        Ok(()) => removed = true,
+        Err(e) => match e.kind() {
+          std::io::ErrorKind::NotFound => {}
+          _ => return Err(UserDataError::UserDataSaveError(e)),
+        },
+      }
+    }
+    Ok(removed)
+  }
 }